@@ -1,4 +1,7 @@
-use clap::Parser;
+use crate::template::Template;
+use chrono::{ NaiveDate, NaiveDateTime, NaiveTime };
+use clap::{ Parser, ValueEnum };
+use indicatif::HumanBytes;
 use pretty_duration::pretty_duration;
 use serde::Deserialize;
 use std::{
@@ -23,12 +26,18 @@ pub struct Args {
     #[arg(short, long, default_value_t = 256, help = "Simultaneous downloads (1-512)")]
     threads: usize,
 
+    #[arg(long, help = "Lower simultaneous downloads when rate limited, raise them again on success")]
+    pub adaptive: bool,
+
     #[arg(short = 'f', long = "input-file", help = "File with URLs, can be used multiple times")]
     pub input_files: Option<Vec<String>>,
 
     #[arg(short, long, default_value = "kumono", help = "Base directory for downloads")]
     pub output_path: String,
 
+    #[arg(long, help = "Directory for partial downloads, moved to the output path once verified")]
+    pub temp_dir: Option<String>,
+
     #[arg(
         short,
         long,
@@ -57,9 +66,98 @@ pub struct Args {
     )]
     exclude: Option<Vec<String>>,
 
+    #[arg(
+        long,
+        value_parser = try_date_since,
+        help = "Skip posts published before this date (YYYY-MM-DD[THH:MM:SS])",
+        help_heading = "Filtering"
+    )]
+    pub since: Option<NaiveDateTime>,
+
+    #[arg(
+        long,
+        value_parser = try_date_until,
+        help = "Skip posts published after this date (YYYY-MM-DD[THH:MM:SS])",
+        help_heading = "Filtering"
+    )]
+    pub until: Option<NaiveDateTime>,
+
     #[arg(short, long, help = "Log hashes, skip moved/deleted file downloads")]
     pub download_archive: bool,
 
+    #[arg(long, help = "Use original file names, with the hash attached to keep them unique")]
+    pub original_names: bool,
+
+    #[arg(long, help = "Set file modification times to the post's publish date")]
+    pub mtime: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = Layout::Flat,
+        conflicts_with = "path_template",
+        help = "Store all files of a target in one directory, or one subdirectory per post"
+    )]
+    pub layout: Layout,
+
+    #[arg(
+        long,
+        conflicts_with = "dedup",
+        help = "Store files once in \"{output}/objects\", with symlinks in target directories"
+    )]
+    pub objects: bool,
+
+    #[arg(
+        long,
+        value_parser = Template::parse,
+        help = "Directory template, relative to the output path [default: {service}/{user}]",
+        help_heading = "Templates"
+    )]
+    pub path_template: Option<Template>,
+
+    #[arg(
+        long,
+        value_parser = Template::parse,
+        help = "File name template [default: {hash}.{ext}]",
+        help_heading = "Templates"
+    )]
+    pub filename_template: Option<Template>,
+
+    #[arg(
+        long,
+        help = "Save post metadata as {post_id}.post.json next to the post's files",
+        help_heading = "Metadata"
+    )]
+    pub write_json: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        value_name = "FORMAT",
+        help = "Save post text as {post_id}.post.md/.html next to the post's files",
+        help_heading = "Metadata"
+    )]
+    pub write_content: Option<ContentFormat>,
+
+    #[arg(
+        long,
+        conflicts_with = "path_template",
+        help = "Save Mega/Google Drive/Dropbox/Pixeldrain links to links.txt/.jsonl (per target)",
+        help_heading = "Metadata"
+    )]
+    pub extract_links: bool,
+
+    #[arg(
+        short,
+        long,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "1",
+        value_name = "PAGES",
+        help = "Log post IDs, stop after N pages of previously seen posts [default: 1]"
+    )]
+    pub update: Option<usize>,
+
     #[arg(short, long, default_value_t = 5, help_heading = "Connection")]
     pub max_tries: usize,
 
@@ -106,6 +204,112 @@ pub struct Args {
     )]
     pub server_error_delay: Duration,
 
+    #[arg(
+        long,
+        value_parser = try_duration_from_secs,
+        default_value = "120",
+        help = "Upper limit for (exponentially growing) retry delays",
+        help_heading = "Connection"
+    )]
+    pub max_backoff: Duration,
+
+    #[arg(
+        long,
+        default_value_t = 1,
+        help = "Connections per file, for files of 64 MiB and more (1-16)",
+        help_heading = "Connection"
+    )]
+    segments: usize,
+
+    #[arg(
+        long,
+        value_name = "RATE",
+        value_parser = try_rate,
+        help = "Maximum total download speed in bytes per second (e.g. 500K, 20M)",
+        help_heading = "Connection"
+    )]
+    pub limit_rate: Option<u64>,
+
+    #[arg(
+        long,
+        value_parser = try_base_url,
+        default_value = "https://coomer.st",
+        help = "Base URL for coomer API, data and login requests",
+        help_heading = "Sites"
+    )]
+    pub coomer_url: String,
+
+    #[arg(
+        long,
+        value_parser = try_base_url,
+        default_value = "https://kemono.cr",
+        help = "Base URL for kemono API, data and login requests",
+        help_heading = "Sites"
+    )]
+    pub kemono_url: String,
+
+    #[arg(
+        long,
+        value_enum,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "plan",
+        value_name = "MODE",
+        help = "Print files and their destination instead of downloading (size: fetch file sizes)"
+    )]
+    pub dry_run: Option<DryRun>,
+
+    #[arg(
+        long,
+        value_enum,
+        value_name = "FORMAT",
+        conflicts_with = "dry_run",
+        help = "Print URLs of all files (to stdout) instead of downloading"
+    )]
+    pub export_urls: Option<ExportFormat>,
+
+    #[arg(
+        long,
+        value_enum,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "report",
+        value_name = "ACTION",
+        conflicts_with_all = ["dry_run", "export_urls", "path_template"],
+        help = "Re-hash downloaded files instead of downloading (delete/quarantine: remove mismatches)"
+    )]
+    pub verify: Option<VerifyAction>,
+
+    #[arg(
+        long,
+        value_enum,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "trash",
+        value_name = "ACTION",
+        conflicts_with_all = ["update", "since", "until", "path_template", "verify"],
+        help = "Remove local files that are no longer present remotely (trash: move to \"{output}/trash\")"
+    )]
+    pub prune: Option<PruneAction>,
+
+    #[arg(
+        long,
+        default_value_t = 10.0,
+        value_name = "PERCENT",
+        requires = "prune",
+        help = "Skip pruning if more than this share of a target's files would be removed"
+    )]
+    pub prune_threshold: f64,
+
+    #[arg(
+        long,
+        value_enum,
+        value_name = "MODE",
+        conflicts_with_all = ["verify", "export_urls"],
+        help = "Link files that already exist anywhere in the output path instead of downloading them"
+    )]
+    pub dedup: Option<DedupMode>,
+
     #[arg(short, long, help = "Print configuration values")]
     pub show_config: bool,
     // #[arg(short, long, help = "Print all error messages")]
@@ -133,15 +337,139 @@ pub struct Args {
     // pub cookies_from_browser: Option<String>,
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Layout {
+    // {output}/{service}/{user}/{file}
+    Flat,
+    // {output}/{service}/{user}/{post_id} - {post_title}/{file}
+    Post,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum DryRun {
+    // status, URL and path of every file
+    Plan,
+    // ... plus remote file sizes (HEAD requests)
+    Size,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum DedupMode {
+    Hardlink,
+    // copy-on-write clone, falls back to a regular copy
+    Reflink,
+    // relative symbolic link
+    Symlink,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum PruneAction {
+    // move orphaned files to "{output}/trash"
+    Trash,
+    Delete,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum VerifyAction {
+    // only list mismatched, leftover temporary and unexpected files
+    Report,
+    // ... and delete mismatched files
+    Delete,
+    // ... and move mismatched files to "{output}/quarantine"
+    Quarantine,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    // aria2c input file (URL with dir, out and checksum options)
+    Aria2,
+    // one URL per line (wget -i, curl)
+    Plain,
+    // one JSON object per line
+    Jsonl,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentFormat {
+    Markdown,
+    Html,
+}
+
 fn try_duration_from_secs(arg: &str) -> anyhow::Result<Duration, num::ParseIntError> {
     Ok(Duration::from_secs(arg.parse::<u64>()?.clamp(1, u64::MAX)))
 }
 
+fn try_date_since(arg: &str) -> anyhow::Result<NaiveDateTime, chrono::ParseError> {
+    try_date(arg, NaiveTime::MIN)
+}
+
+// a plain date includes the whole day
+fn try_date_until(arg: &str) -> anyhow::Result<NaiveDateTime, chrono::ParseError> {
+    try_date(arg, NaiveTime::from_hms_micro_opt(23, 59, 59, 999_999).unwrap())
+}
+
+fn try_date(arg: &str, time: NaiveTime) -> anyhow::Result<NaiveDateTime, chrono::ParseError> {
+    NaiveDateTime::parse_from_str(arg, "%Y-%m-%dT%H:%M:%S").or_else(|_| {
+        Ok(NaiveDate::parse_from_str(arg, "%Y-%m-%d")?.and_time(time))
+    })
+}
+
+// "20M" -> 20 MiB, suffixes are binary (K, M, G) and case insensitive
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn try_rate(arg: &str) -> anyhow::Result<u64> {
+    let arg = arg.trim();
+
+    let (number, multiplier) = match arg.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&arg[..arg.len() - 1], 1024.0),
+        Some('M') => (&arg[..arg.len() - 1], 1024.0 * 1024.0),
+        Some('G') => (&arg[..arg.len() - 1], 1024.0 * 1024.0 * 1024.0),
+        _ => (arg, 1.0),
+    };
+
+    let rate = number.parse::<f64>().map(|number| (number * multiplier).round())?;
+
+    if rate.is_finite() && rate >= 1.0 {
+        Ok(rate as u64)
+    } else {
+        Err(anyhow::anyhow!("expected a positive number of bytes, e.g. 500K or 20M"))
+    }
+}
+
+fn try_base_url(arg: &str) -> anyhow::Result<String> {
+    let url = arg.trim_end_matches('/');
+
+    if
+        let Some((scheme, host)) = url.split_once("://") &&
+        matches!(scheme, "http" | "https") &&
+        !host.is_empty()
+    {
+        Ok(url.to_string())
+    } else {
+        Err(anyhow::anyhow!("expected http(s)://host[:port][/path]"))
+    }
+}
+
 impl Args {
+    // nothing is downloaded or written, files are only listed
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run.is_some() || self.export_urls.is_some()
+    }
+
     pub fn threads(&self) -> usize {
         self.threads.clamp(1, 512)
     }
 
+    pub fn segments(&self) -> usize {
+        self.segments.clamp(1, 16)
+    }
+
     pub fn included(&self) -> Option<HashSet<String>> {
         Self::process_exts(self.include.as_ref()?)
     }
@@ -150,6 +478,18 @@ impl Args {
         Self::process_exts(self.exclude.as_ref()?)
     }
 
+    // posts without a known date are always kept
+    pub fn in_date_range(&self, date: Option<NaiveDateTime>) -> bool {
+        date.is_none_or(|date| {
+            self.since.is_none_or(|since| date >= since) &&
+                self.until.is_none_or(|until| date <= until)
+        })
+    }
+
+    pub fn before_since(&self, date: Option<NaiveDateTime>) -> bool {
+        self.since.is_some_and(|since| date.is_some_and(|date| date < since))
+    }
+
     fn process_exts(exts: &[String]) -> Option<HashSet<String>> {
         let mut unique_exts = HashSet::with_capacity(exts.len());
 
@@ -174,13 +514,23 @@ impl Display for Args {
 
         write!(
             f,
-            "Threads: {} / Proxy: {} / Timeout: (Connect: {} / Read: {}) / Backoff: (Rate Limit: {} / Server Error: {})",
+            "Threads: {}{} / Segments: {} / Proxy: {} / Rate Limit: {} / Sites: (Coomer: {} / Kemono: {}) / Timeout: (Connect: {} / Read: {}) / Backoff: (Rate Limit: {} / Server Error: {} / Max: {})",
             self.threads(),
+            if self.adaptive { " (adaptive)" } else { "" },
+            self.segments(),
             self.proxy.as_ref().map_or("None", |p| p),
+            self.limit_rate.map_or_else(|| String::from("None"), |rate| {
+                let mut buf = HumanBytes(rate).to_string();
+                buf.push_str("/s");
+                buf
+            }),
+            self.coomer_url,
+            self.kemono_url,
             pd(&self.connect_timeout),
             pd(&self.read_timeout),
             pd(&self.rate_limit_backoff),
-            pd(&self.server_error_delay)
+            pd(&self.server_error_delay),
+            pd(&self.max_backoff)
         )
     }
 }
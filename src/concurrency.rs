@@ -0,0 +1,114 @@
+use crate::cli::ARGUMENTS;
+use anyhow::Result;
+use std::{ sync::{ Arc, Mutex }, time::{ Duration, Instant } };
+use tokio::sync::{ OwnedSemaphorePermit, Semaphore };
+
+// limit reductions within this interval are caused by the same wave of rate limiting
+const DECREASE_INTERVAL: Duration = Duration::from_secs(5);
+
+// Limits simultaneous downloads to --threads. With --adaptive, the limit is halved
+// whenever downloads are rate limited (or hit server errors), and raised by one
+// for every `limit` successfully completed downloads (AIMD).
+pub struct Concurrency {
+    sem: Arc<Semaphore>,
+    max: usize,
+    state: Mutex<State>,
+}
+
+struct State {
+    limit: usize,
+    // permits that are still held by downloads, but have to be removed once released
+    debt: usize,
+    successes: usize,
+    decreased: Option<Instant>,
+}
+
+pub struct Permit {
+    permit: Option<OwnedSemaphorePermit>,
+    concurrency: Arc<Concurrency>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut state = self.concurrency.state.lock().unwrap();
+
+        if state.debt > 0 {
+            state.debt -= 1;
+
+            if let Some(permit) = self.permit.take() {
+                permit.forget();
+            }
+        }
+    }
+}
+
+impl Concurrency {
+    pub fn new(max: usize) -> Arc<Self> {
+        Arc::new(Self {
+            sem: Arc::new(Semaphore::new(max)),
+            max,
+            state: Mutex::new(State {
+                limit: max,
+                debt: 0,
+                successes: 0,
+                decreased: None,
+            }),
+        })
+    }
+
+    pub async fn acquire(self: &Arc<Self>) -> Result<Permit> {
+        Ok(Permit {
+            permit: Some(self.sem.clone().acquire_owned().await?),
+            concurrency: self.clone(),
+        })
+    }
+
+    pub fn limit(&self) -> usize {
+        self.state.lock().unwrap().limit
+    }
+
+    // a download is waiting due to rate limiting or server errors
+    pub fn decrease(&self) {
+        if !ARGUMENTS.adaptive {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+
+        if state.decreased.is_some_and(|decreased| decreased.elapsed() < DECREASE_INTERVAL) {
+            return;
+        }
+
+        let limit = (state.limit / 2).max(1);
+        let reduction = state.limit - limit;
+
+        state.debt += reduction - self.sem.forget_permits(reduction);
+        state.limit = limit;
+        state.successes = 0;
+        state.decreased = Some(Instant::now());
+    }
+
+    // a download was completed
+    pub fn increase(&self) {
+        if !ARGUMENTS.adaptive {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+
+        state.successes += 1;
+
+        if state.successes < state.limit || state.limit == self.max {
+            return;
+        }
+
+        state.successes = 0;
+        state.limit += 1;
+
+        if state.debt > 0 {
+            state.debt -= 1;
+        } else {
+            self.sem.add_permits(1);
+        }
+    }
+}
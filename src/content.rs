@@ -0,0 +1,141 @@
+use crate::{
+    cli::{ ARGUMENTS, ContentFormat },
+    file::{ PostFile, relative_path },
+    post::RE_INLINE,
+    target::Target,
+};
+use anyhow::Result;
+use regex::{ Captures, Regex };
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    path::Path,
+    sync::{ Arc, LazyLock },
+};
+
+static RE_SCRIPT: LazyLock<Regex> = LazyLock::new(||
+    Regex::new(r"(?is)<(?:script|style)\b.*?</(?:script|style)\s*>").unwrap()
+);
+
+impl ContentFormat {
+    pub fn as_ext(self) -> &'static str {
+        match self {
+            ContentFormat::Markdown => "md",
+            ContentFormat::Html => "html",
+        }
+    }
+}
+
+// Post content with references to downloaded files rewritten to relative
+// local paths. Everything else pointing to "/data/" is made absolute, so the
+// exported file still works for files that were not downloaded.
+pub fn render(
+    format: ContentFormat,
+    title: Option<&str>,
+    content: &str,
+    files: &[Arc<PostFile>],
+    dir: &Path,
+    target: &Target
+) -> Result<String> {
+    let content = rewrite_data_refs(&RE_SCRIPT.replace_all(content, ""), files, dir, target);
+    let title = title.map(str::trim).filter(|title| !title.is_empty());
+
+    let mut buf = String::with_capacity(content.len() + 256);
+
+    match format {
+        ContentFormat::Markdown => {
+            if let Some(title) = title {
+                let _ = write!(buf, "# {title}\n\n");
+            }
+
+            buf.push_str(htmd::convert(&content)?.trim());
+            buf.push('\n');
+        }
+        ContentFormat::Html => {
+            let title = title.map(escape_html).unwrap_or_default();
+
+            let _ = write!(
+                buf,
+                "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{title}</title>\n</head>\n<body>\n"
+            );
+
+            if !title.is_empty() {
+                let _ = writeln!(buf, "<h1>{title}</h1>");
+            }
+
+            let _ = write!(buf, "{content}\n</body>\n</html>\n");
+        }
+    }
+
+    Ok(buf)
+}
+
+fn rewrite_data_refs(content: &str, files: &[Arc<PostFile>], dir: &Path, target: &Target) -> String {
+    let included = ARGUMENTS.included();
+    let excluded = ARGUMENTS.excluded();
+
+    let local: HashMap<&str, &Arc<PostFile>> = files
+        .iter()
+        .filter(|file| {
+            let ext = file.get_ext();
+
+            included.as_ref().is_none_or(|exts| ext.is_some_and(|ext| exts.contains(ext))) &&
+                excluded.as_ref().is_none_or(|exts| ext.is_none_or(|ext| !exts.contains(ext)))
+        })
+        .map(|file| (file.get_path(), file))
+        .collect();
+
+    RE_INLINE.replace_all(content, |caps: &Captures| {
+        let attr = &caps["attr"];
+        let path = &caps["path"];
+
+        let mut buf = String::with_capacity(attr.len() + path.len() + 32);
+        buf.push_str(attr);
+
+        if let Some(file) = local.get(path) {
+            buf.push_str(&to_relative_url(&file.to_pathbuf(target), dir));
+        } else {
+            let _ = write!(buf, "{}/data{path}", target.as_service().base_url());
+        }
+
+        buf
+    }).into_owned()
+}
+
+// relative, percent-encoded URL of a local file, as seen from the content file's directory
+fn to_relative_url(path: &Path, dir: &Path) -> String {
+    let mut url = String::new();
+
+    for (i, component) in relative_path(path, dir).iter().enumerate() {
+        if i != 0 {
+            url.push('/');
+        }
+
+        for c in component.to_string_lossy().chars() {
+            match c {
+                ' ' | '"' | '#' | '%' | '\'' | '(' | ')' | '<' | '>' | '?' | '[' | ']' => {
+                    let _ = write!(url, "%{:02X}", c as u32);
+                }
+                c => url.push(c),
+            }
+        }
+    }
+
+    url
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
@@ -0,0 +1,107 @@
+use crate::{
+    cli::{ ARGUMENTS, DedupMode },
+    file::relative_path,
+    pretty::with_word,
+    verify::{ self, Kind },
+};
+use anyhow::{ Context, Result, bail };
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    fs::{ self, File },
+    io,
+    path::{ Path, PathBuf },
+    sync::{ LazyLock, Mutex },
+};
+use tokio::task;
+
+#[cfg(target_os = "linux")]
+use std::os::fd::AsRawFd;
+#[cfg(unix)]
+use std::os::unix::fs::symlink;
+#[cfg(windows)]
+use std::os::windows::fs::symlink_file as symlink;
+
+// hash -> first local file with that hash
+static INDEX: LazyLock<Mutex<HashMap<String, PathBuf>>> = LazyLock::new(Mutex::default);
+
+// Indexes all files in the output path whose name contains a hash (--dedup).
+// Files moved aside by --verify/--prune and symlinks are left out.
+pub fn try_build_index() -> Result<()> {
+    let output = Path::new(&ARGUMENTS.output_path);
+
+    if !output.is_dir() {
+        return Ok(());
+    }
+
+    let skip = ["db", "quarantine", "trash"].map(|dir| output.join(dir));
+
+    let mut index = INDEX.lock().unwrap();
+
+    for path in verify::try_walk(output, &skip)? {
+        if !path.is_symlink() && let Kind::Hashed(hash) = verify::classify(&path) {
+            index.entry(hash).or_insert(path);
+        }
+    }
+
+    eprintln!("Dedup: indexed {}", with_word(index.len(), "file"));
+
+    Ok(())
+}
+
+pub fn find(hash: &str) -> Option<PathBuf> {
+    INDEX.lock().unwrap().get(hash).cloned()
+}
+
+// completed downloads can be linked by later targets
+pub fn insert(hash: &str, path: PathBuf) {
+    INDEX.lock().unwrap().entry(hash.to_string()).or_insert(path);
+}
+
+pub async fn try_link(mode: DedupMode, src: PathBuf, dest: PathBuf) -> Result<()> {
+    task::spawn_blocking(move || {
+        // the indexed file may have been removed since (e.g. by --prune)
+        if !src.is_file() {
+            bail!("indexed file no longer exists: {}", src.display());
+        }
+
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        match mode {
+            DedupMode::Hardlink => fs::hard_link(&src, &dest),
+            DedupMode::Reflink => reflink(&src, &dest),
+            DedupMode::Symlink => {
+                // relative, so links survive moving the output directory
+                symlink(relative_path(&src, dest.parent().unwrap_or(Path::new(""))), &dest)
+            }
+        }.with_context(|| {
+            let mut buf = String::with_capacity(
+                12 + src.as_os_str().len() + dest.as_os_str().len()
+            );
+            let _ = write!(buf, "link file: {} -> {}", src.display(), dest.display());
+            buf
+        })
+    }).await?
+}
+
+#[cfg(target_os = "linux")]
+fn reflink(src: &Path, dest: &Path) -> io::Result<()> {
+    let src_file = File::open(src)?;
+    let dest_file = File::options().write(true).create(true).truncate(true).open(dest)?;
+
+    // FICLONE only takes two valid file descriptors, which outlive the call
+    if unsafe { libc::ioctl(dest_file.as_raw_fd(), libc::FICLONE, src_file.as_raw_fd()) } == 0 {
+        return Ok(());
+    }
+
+    // not supported by the file system (or across file systems)
+    fs::copy(src, dest).map(|_| ())
+}
+
+// std::fs::copy already clones files where possible (e.g. on macOS)
+#[cfg(not(target_os = "linux"))]
+fn reflink(src: &Path, dest: &Path) -> io::Result<()> {
+    fs::copy(src, dest).map(|_| ())
+}
@@ -1,53 +1,62 @@
-use crate::{ cli::ARGUMENTS, http::CLIENT, progress::DownloadAction, target::Target };
+use crate::{
+    cli::{ ARGUMENTS, DedupMode, Layout },
+    dedup,
+    http::CLIENT,
+    limit,
+    post::PostMeta,
+    progress::DownloadAction,
+    retry,
+    shutdown::{ self, Interrupted },
+    target::Target,
+};
 use anyhow::{ Context, Result, anyhow, format_err };
+use futures::future::join_all;
 use futures_util::StreamExt;
 use regex::Regex;
-use reqwest::StatusCode;
+use reqwest::{ StatusCode, header::CONTENT_LENGTH };
 use serde::Deserialize;
+use sha2::{ Digest, Sha256 };
 use std::{
     error::Error,
+    ffi::OsString,
     fmt::{ self, Display, Formatter, Write },
-    io::SeekFrom,
+    hash::{ self, Hasher },
+    io::{ ErrorKind, SeekFrom },
     ops::Range,
-    path::PathBuf,
-    process::exit,
+    path::{ Component, Path, PathBuf },
     sync::{ Arc, LazyLock },
-    time::Duration,
+    time::{ Duration, SystemTime },
 };
 use tokio::{
     fs::{ self, File },
-    io::{ AsyncSeekExt, AsyncWriteExt },
-    sync::mpsc::UnboundedSender,
-    time::sleep,
+    io::{ AsyncReadExt, AsyncSeekExt, AsyncWriteExt },
+    sync::{ Mutex, mpsc::UnboundedSender },
+    task,
 };
 
 const CHUNK_SIZE: u64 = 4 * 1024 * 1024; // 4 MiB
+const SEGMENT_MIN_SIZE: u64 = 64 * 1024 * 1024; // 64 MiB
 
-static HASH_RE: LazyLock<Regex> = LazyLock::new(||
+pub static HASH_RE: LazyLock<Regex> = LazyLock::new(||
     Regex::new(r"(?<hash>[0-9a-f]{64})(?:\..+)?$").unwrap()
 );
 
 #[derive(Deserialize, PartialEq, Eq, Hash, Debug)]
 pub struct PostFileRaw {
-    // Deserializing the name field breaks our hashset's uniqueness guarantee;
-    // the same file may be known under different names, leading to a race
-    // condition where multiple concurrent tasks write to the same file,
-    // causing corruption & size mismatches. hash/size mismatches lead to
-    // file deletion, where a second race condition can occur.
-    // This corruption also causes offsets to be incorrect,
-    // leading to HTTP 416 (Range Not Satisfiable) responses.
-    //
-    // To prevent issues stemming from redundant file names, storing
-    // files in a separate subdirectory for each post or attaching the hash
-    // to the file name can be considered.
-    // This would require the current storage behavior to be changed, though
-    // the addition of a dedicated mode for this behavior would also be possible.
+    // The same file may be known under different names, so the name must not
+    // be part of our hashset's uniqueness guarantee. Otherwise, multiple
+    // concurrent tasks could write to the same file, causing corruption & size
+    // mismatches. hash/size mismatches lead to file deletion, where a second
+    // race condition can occur. This corruption also causes offsets to be
+    // incorrect, leading to HTTP 416 (Range Not Satisfiable) responses.
     //
-    // pub name: Option<String>,
+    // PostFile equality is therefore based on the remote path only, and the
+    // original name is only used with --original-names, where the hash (or the
+    // post ID and index for legacy files) is attached to keep local names unique.
+    pub name: Option<String>,
     pub path: Option<String>,
 }
 
-#[derive(PartialEq, Eq, Hash)]
 pub struct PostFile {
     base: String,
     path_range: Range<usize>,
@@ -55,16 +64,94 @@ pub struct PostFile {
     temp_range: Range<usize>,
     pub ext_range: Option<Range<usize>>,
     pub hash_range: Option<Range<usize>>,
+    // "{local name}.temp", if the local name differs from the remote one
+    local: Option<String>,
+    original_name: Option<String>,
+    post: Arc<PostMeta>,
+    index: usize,
+}
+
+impl PartialEq for PostFile {
+    fn eq(&self, other: &Self) -> bool {
+        self.get_path() == other.get_path()
+    }
+}
+
+impl Eq for PostFile {}
+
+impl hash::Hash for PostFile {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        hash::Hash::hash(self.get_path(), state);
+    }
 }
 
 impl Display for PostFile {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.get_name())
+        write!(f, "{}", self.get_local_name())
     }
 }
 
+// replaces characters which are not allowed in file names on common platforms
+pub fn sanitize(component: &str) -> String {
+    let clean: String = component
+        .chars()
+        .map(|c| {
+            if c.is_control() || matches!(c, '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|') {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect();
+
+    let clean = clean.trim_start_matches(' ').trim_end_matches(['.', ' ']);
+
+    if clean.is_empty() { String::from("_") } else { clean.to_string() }
+}
+
+// path of a file as seen from a directory, e.g. "../{post}/{name}"
+pub fn relative_path(path: &Path, dir: &Path) -> PathBuf {
+    let path: Vec<Component> = path.components().collect();
+    let dir: Vec<Component> = dir.components().collect();
+
+    let common = path
+        .iter()
+        .zip(&dir)
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut relative = PathBuf::new();
+
+    for _ in common..dir.len() {
+        relative.push("..");
+    }
+
+    relative.extend(&path[common..]);
+
+    relative
+}
+
+pub fn truncate(text: &str, max_len: usize) -> &str {
+    if text.len() <= max_len {
+        return text;
+    }
+
+    let mut end = max_len;
+
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    &text[..end]
+}
+
 impl PostFile {
-    pub fn new(path: String) -> Arc<Self> {
+    pub fn new(
+        path: String,
+        original_name: Option<String>,
+        post: Arc<PostMeta>,
+        index: usize
+    ) -> Arc<Self> {
         let path_len = path.len();
         let path_range = Range {
             start: 0,
@@ -95,14 +182,49 @@ impl PostFile {
 
         let base = path + ".temp";
 
-        Arc::new(Self {
+        let mut file = Self {
             base,
             path_range,
             name_range,
             temp_range,
             ext_range,
             hash_range,
-        })
+            local: None,
+            original_name: original_name.map(|name| sanitize(&name)),
+            post,
+            index,
+        };
+
+        if ARGUMENTS.original_names && let Some(original_name) = &file.original_name {
+            file.local = file.make_original_name(original_name);
+        }
+
+        Arc::new(file)
+    }
+
+    // "{original name}_{hash}.{ext}", falls back to "{post ID}-{index}" for legacy files,
+    // as a post may contain multiple files of the same name
+    fn make_original_name(&self, name: &str) -> Option<String> {
+        let suffix = match self.get_hash() {
+            Some(hash) => hash.to_string(),
+            None => {
+                let id = self.post.id.as_deref()?;
+                let mut suffix = String::with_capacity(id.len() + 4);
+                let _ = write!(suffix, "{id}-{}", self.index);
+                suffix
+            }
+        };
+
+        let stem = truncate(Self::stem(name), 128);
+
+        let mut local = String::with_capacity(stem.len() + 1 + suffix.len() + 16);
+
+        let _ = match self.get_ext() {
+            Some(ext) => write!(local, "{stem}_{suffix}.{ext}.temp"),
+            None => write!(local, "{stem}_{suffix}.temp"),
+        };
+
+        Some(local)
     }
 
     pub fn get_path(&self) -> &str {
@@ -117,6 +239,34 @@ impl PostFile {
         &self.base[self.temp_range.start..self.temp_range.end]
     }
 
+    pub fn get_post(&self) -> &PostMeta {
+        &self.post
+    }
+
+    pub fn get_index(&self) -> usize {
+        self.index
+    }
+
+    // original file name without extension, falls back to the remote name
+    pub fn get_original_stem(&self) -> &str {
+        Self::stem(self.original_name.as_deref().unwrap_or_else(|| self.get_name()))
+    }
+
+    fn stem(name: &str) -> &str {
+        match name.rsplit_once('.') {
+            Some((stem, _)) if !stem.is_empty() => stem,
+            _ => name,
+        }
+    }
+
+    pub fn get_local_name(&self) -> &str {
+        self.local.as_ref().map_or_else(|| self.get_name(), |local| &local[..local.len() - 5])
+    }
+
+    pub fn get_local_temp(&self) -> &str {
+        self.local.as_deref().unwrap_or_else(|| self.get_temp())
+    }
+
     pub fn get_ext(&self) -> Option<&str> {
         self.ext_range.as_ref().map(|e_r| &self.base[e_r.start..e_r.end])
     }
@@ -126,71 +276,314 @@ impl PostFile {
     }
 
     pub fn to_url(&self, target: &Target) -> String {
-        let host = target.as_service().host();
+        let base = target.as_service().base_url();
         let path = self.get_path();
-        let mut url = String::with_capacity(8 + host.len() + 5 + path.len());
-        let _ = write!(url, "https://{host}/data{path}");
+        let mut url = String::with_capacity(base.len() + 5 + path.len());
+        let _ = write!(url, "{base}/data{path}");
         url
     }
 
     pub fn to_pathbuf(&self, target: &Target) -> PathBuf {
-        let mut path = target.as_pathbuf().clone();
-        path.push(self.get_name());
-        path
+        self.make_pathbuf(target, false)
     }
 
+    // With --temp-dir, the path relative to the output path is kept there.
     pub fn to_temp_pathbuf(&self, target: &Target) -> PathBuf {
-        let mut path = target.as_pathbuf().clone();
-        path.push(self.get_temp());
+        let path = self.to_staging_pathbuf(target);
+
+        match &ARGUMENTS.temp_dir {
+            Some(temp_dir) => {
+                Path::new(temp_dir).join(
+                    path.strip_prefix(&ARGUMENTS.output_path).unwrap_or(&path)
+                )
+            }
+            None => path,
+        }
+    }
+
+    // "{store path}.temp", next to the final file
+    fn to_staging_pathbuf(&self, target: &Target) -> PathBuf {
+        match self.to_object_pathbuf() {
+            Some(object) => {
+                let mut path = object.into_os_string();
+                path.push(".temp");
+                PathBuf::from(path)
+            }
+            None => self.make_pathbuf(target, true),
+        }
+    }
+
+    // where the file's contents are stored, the object or the file itself
+    fn to_store_pathbuf(&self, target: &Target) -> PathBuf {
+        self.to_object_pathbuf().unwrap_or_else(|| self.to_pathbuf(target))
+    }
+
+    // "{output}/objects/ab/cd/{hash}.{ext}" (--objects), legacy files are stored as usual
+    fn to_object_pathbuf(&self) -> Option<PathBuf> {
+        if !ARGUMENTS.objects {
+            return None;
+        }
+
+        let hash = self.get_hash()?;
+
+        let mut name = String::with_capacity(hash.len() + 16);
+        name.push_str(hash);
+
+        if let Some(ext) = self.get_ext() {
+            let _ = write!(name, ".{ext}");
+        }
+
+        Some(
+            PathBuf::from_iter([&ARGUMENTS.output_path, "objects", &hash[..2], &hash[2..4], &name])
+        )
+    }
+
+    fn make_pathbuf(&self, target: &Target, temp: bool) -> PathBuf {
+        let mut path = if let Some(template) = &ARGUMENTS.path_template {
+            let mut path = PathBuf::from(&ARGUMENTS.output_path);
+            path.extend(template.render(target, self));
+
+            if let Some(dir) = target.as_subtype_dir() {
+                path.push(dir);
+            }
+
+            path
+        } else {
+            let mut path = target.as_pathbuf().clone();
+
+            if ARGUMENTS.layout == Layout::Post && let Some(dir) = self.post.to_dir_name() {
+                path.push(dir);
+            }
+
+            path
+        };
+
+        if let Some(template) = &ARGUMENTS.filename_template {
+            let mut components = template.render(target, self);
+
+            if components.is_empty() {
+                components.push(self.get_name().to_string());
+            }
+
+            if temp && let Some(name) = components.last_mut() {
+                name.push_str(".temp");
+            }
+
+            path.extend(components);
+        } else if temp {
+            path.push(self.get_local_temp());
+        } else {
+            path.push(self.get_local_name());
+        }
+
         path
     }
 
     pub async fn try_open(&self, target: &Target) -> Result<File> {
+        let path = self.to_temp_pathbuf(target);
+
+        // templates may place files in (per-post) subdirectories
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+
         File::options()
             .append(true)
             .create(true)
             .truncate(false)
-            .open(&self.to_temp_pathbuf(target)).await
+            .open(&path).await
             .with_context(|| {
-                let mut buf = String::with_capacity(31 + self.temp_range.len());
-                let _ = write!(buf, "Failed to open temporary file: {}", self.get_temp());
+                let temp = self.get_local_temp();
+                let mut buf = String::with_capacity(31 + temp.len());
+                let _ = write!(buf, "Failed to open temporary file: {temp}");
                 buf
             })
     }
 
     pub async fn hash(&self, target: &Target) -> Result<String> {
         sha256::try_async_digest(&self.to_temp_pathbuf(target)).await.with_context(|| {
-            let mut buf = String::with_capacity(15 + self.temp_range.len());
-            let _ = write!(buf, "hash tempfile: {}", self.get_temp());
+            let temp = self.get_local_temp();
+            let mut buf = String::with_capacity(15 + temp.len());
+            let _ = write!(buf, "hash tempfile: {temp}");
             buf
         })
     }
 
+    // hashes the partial download of a previous run, which is continued from here
+    async fn try_hash_prefix(&self, target: &Target) -> Result<Sha256> {
+        let mut hasher = Sha256::new();
+
+        let mut temp_file = File::open(self.to_temp_pathbuf(target)).await?;
+        let mut buf = vec![0; 1024 * 1024];
+
+        loop {
+            match temp_file.read(&mut buf).await? {
+                0 => {
+                    break;
+                }
+                n => hasher.update(&buf[..n]),
+            }
+        }
+
+        Ok(hasher)
+    }
+
+    // With --objects, regular files at the file's place in the target directory
+    // count as well, they are moved to the store by `try_link_view`.
     pub async fn try_exists(&self, target: &Target) -> Result<bool> {
-        fs::try_exists(self.to_pathbuf(target)).await.with_context(|| {
-            let mut buf = String::with_capacity(22 + self.temp_range.len());
-            let _ = write!(buf, "check if file exists: {}", self.get_temp());
+        if
+            ARGUMENTS.objects &&
+            fs::symlink_metadata(self.to_pathbuf(target)).await.is_ok_and(|meta| meta.is_file())
+        {
+            return Ok(true);
+        }
+
+        fs::try_exists(self.to_store_pathbuf(target)).await.with_context(|| {
+            let name = self.get_local_name();
+            let mut buf = String::with_capacity(22 + name.len());
+            let _ = write!(buf, "check if file exists: {name}");
             buf
         })
     }
 
     pub async fn try_move(&self, target: &Target) -> Result<()> {
-        fs::rename(self.to_temp_pathbuf(target), self.to_pathbuf(target)).await.with_context(|| {
-            let mut buf = String::with_capacity(29 + self.temp_range.len() + self.name_range.len());
-            let _ = write!(
-                buf,
-                "rename tempfile to file: {} -> {}",
-                self.get_temp(),
-                self.get_name()
-            );
+        let path = self.to_store_pathbuf(target);
+        let temp = self.to_temp_pathbuf(target);
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+
+        // objects (--objects) and linked files (--dedup) may already be shared with other
+        // posts, they keep the modification time of the post they were created for
+        let created = !fs::try_exists(&path).await?;
+
+        match fs::rename(&temp, &path).await {
+            Err(err) if err.kind() == ErrorKind::CrossesDevices => {
+                self.try_copy_across(&temp, &path).await?;
+            }
+            result => {
+                result.with_context(|| {
+                    let (temp, name) = (self.get_local_temp(), self.get_local_name());
+                    let mut buf = String::with_capacity(29 + temp.len() + name.len());
+                    let _ = write!(buf, "rename tempfile to file: {temp} -> {name}");
+                    buf
+                })?;
+            }
+        }
+
+        if ARGUMENTS.mtime && created && let Some(published) = self.post.published {
+            self.try_set_mtime(&path, published.and_utc().into()).await?;
+        }
+
+        self.try_link_view(target).await
+    }
+
+    // --temp-dir is on another file system. The copy is staged next to the final
+    // file under a hidden name (".{name}.partial"), so that file only ever appears
+    // complete, and directory watchers skip the copy in progress.
+    async fn try_copy_across(&self, temp: &Path, path: &Path) -> Result<()> {
+        let staging = {
+            let mut name = OsString::from(".");
+            name.push(path.file_name().unwrap_or_default());
+            name.push(".partial");
+            path.with_file_name(name)
+        };
+
+        if let Err(err) = fs::copy(temp, &staging).await {
+            let _ = fs::remove_file(&staging).await;
+
+            return Err(err).with_context(|| {
+                let name = self.get_local_name();
+                let mut buf = String::with_capacity(27 + name.len());
+                let _ = write!(buf, "copy tempfile to output: {name}");
+                buf
+            });
+        }
+
+        fs::rename(&staging, path).await?;
+
+        fs::remove_file(temp).await.with_context(|| {
+            let temp = self.get_local_temp();
+            let mut buf = String::with_capacity(17 + temp.len());
+            let _ = write!(buf, "delete tempfile: {temp}");
+            buf
+        })
+    }
+
+    // Creates the symlink to the file's object in the target directory (--objects).
+    // Regular files (from before --objects was used) are moved to the store first.
+    async fn try_link_view(&self, target: &Target) -> Result<()> {
+        let Some(object) = self.to_object_pathbuf() else {
+            return Ok(());
+        };
+
+        let view = self.to_pathbuf(target);
+
+        match fs::symlink_metadata(&view).await {
+            Ok(meta) if meta.is_file() => {
+                if fs::try_exists(&object).await? {
+                    return Ok(());
+                }
+
+                if let Some(parent) = object.parent() {
+                    fs::create_dir_all(parent).await?;
+                }
+
+                fs::rename(&view, &object).await.with_context(|| {
+                    let name = self.get_local_name();
+                    let mut buf = String::with_capacity(23 + name.len());
+                    let _ = write!(buf, "move file to objects: {name}");
+                    buf
+                })?;
+            }
+            Ok(meta) if meta.is_symlink() => {
+                if fs::try_exists(&view).await? {
+                    return Ok(());
+                }
+
+                // broken, e.g. after the output directory was moved
+                fs::remove_file(&view).await?;
+            }
+            _ => (),
+        }
+
+        dedup::try_link(DedupMode::Symlink, object, view).await
+    }
+
+    async fn try_set_mtime(&self, path: &Path, mtime: SystemTime) -> Result<()> {
+        let file = File::options().write(true).open(path).await?.into_std().await;
+
+        task::spawn_blocking(move || file.set_modified(mtime)).await?.with_context(|| {
+            let name = self.get_local_name();
+            let mut buf = String::with_capacity(29 + name.len());
+            let _ = write!(buf, "set file modification time: {name}");
             buf
         })
     }
 
+    // Copies are made to the temporary file first, so an interrupted copy is
+    // never mistaken for a complete file. Links are created atomically.
+    async fn try_link(&self, target: &Target, mode: DedupMode, src: PathBuf) -> Result<()> {
+        if mode == DedupMode::Reflink {
+            let temp = self.to_temp_pathbuf(target);
+
+            if let Err(err) = dedup::try_link(mode, src, temp.clone()).await {
+                let _ = fs::remove_file(&temp).await;
+                return Err(err);
+            }
+
+            self.try_move(target).await
+        } else {
+            dedup::try_link(mode, src, self.to_pathbuf(target)).await
+        }
+    }
+
     pub async fn try_delete(&self, target: &Target) -> Result<()> {
         fs::remove_file(self.to_temp_pathbuf(target)).await.with_context(|| {
-            let mut buf = String::with_capacity(17 + self.temp_range.len());
-            let _ = write!(buf, "delete tempfile: {}", self.get_temp());
+            let temp = self.get_local_temp();
+            let mut buf = String::with_capacity(17 + temp.len());
+            let _ = write!(buf, "delete tempfile: {temp}");
             buf
         })
     }
@@ -203,86 +596,119 @@ impl PostFile {
         msg_tx.send(DownloadAction::Start)?;
 
         if file.try_exists(target).await? {
+            file.try_link_view(target).await?;
             return Ok(DownloadAction::Skip(file.clone()));
         }
 
+        // falls back to downloading if the file can't be linked
+        if
+            let Some(mode) = ARGUMENTS.dedup &&
+            let Some(hash) = file.get_hash() &&
+            let Some(src) = dedup::find(hash) &&
+            file.try_link(target, mode, src).await.is_ok()
+        {
+            return Ok(DownloadAction::Link(file.clone()));
+        }
+
         let (rsize, rpath) = file.try_fetch_remote_size_and_path(target, &mut msg_tx).await?;
 
-        let mut temp_file = file.try_open(target).await?;
+        // sequential downloads are hashed while writing, segmented ones once complete
+        let mut hasher = None;
 
-        let mut csize = temp_file.seek(SeekFrom::End(0)).await?;
+        if file.use_segments(target, rsize).await? {
+            if let Err(err) = file.try_download_segmented(target, &rpath, rsize, &msg_tx).await {
+                return Ok(DownloadAction::from_error(&err, file.clone()));
+            }
+        } else {
+            let mut temp_file = file.try_open(target).await?;
 
-        loop {
-            let mut range = String::with_capacity(32);
+            let mut csize = temp_file.seek(SeekFrom::End(0)).await?;
 
-            if csize == rsize {
-                break;
-            } else if csize > rsize {
-                file.try_delete(target).await?;
+            if file.get_hash().is_some() && csize <= rsize {
+                hasher = Some(file.try_hash_prefix(target).await?);
+            }
 
-                return Ok(
-                    DownloadAction::Fail(
-                        {
-                            let (csize, rsize) = (csize.to_string(), rsize.to_string());
+            loop {
+                let mut range = String::with_capacity(32);
 
-                            let mut msg = String::with_capacity(
-                                25 + file.name_range.len() + 5 + csize.len() + 6 + rsize.len() + 1
-                            );
+                if csize == rsize {
+                    break;
+                } else if csize > rsize {
+                    file.try_delete(target).await?;
+
+                    return Ok(
+                        DownloadAction::Fail(
+                            {
+                                let (csize, rsize) = (csize.to_string(), rsize.to_string());
+
+                                let mut msg = String::with_capacity(
+                                    25 + file.name_range.len() + 5 + csize.len() + 6 + rsize.len() + 1
+                                );
+                                let _ = write!(
+                                    msg,
+                                    "size mismatch (deleted): {} [l: {csize} | r: {rsize}]",
+                                    file.get_name()
+                                );
+
+                                msg
+                            },
+                            file.clone()
+                        )
+                    );
+                } else if
+                    let Err(err) = file.try_download_range(
+                        &rpath,
+                        &mut temp_file,
+                        &({
+                            range.clear();
                             let _ = write!(
-                                msg,
-                                "size mismatch (deleted): {} [l: {csize} | r: {rsize}]",
-                                file.get_name()
+                                range,
+                                "bytes={csize}-{}",
+                                (rsize - 1).min(csize + CHUNK_SIZE - 1)
                             );
-
-                            msg
-                        },
-                        file.clone()
-                    )
-                );
-            } else if
-                let Err(err) = file.try_download_range(
-                    &rpath,
-                    &mut temp_file,
-                    &({
-                        range.clear();
-                        let _ = write!(
-                            range,
-                            "bytes={csize}-{}",
-                            (rsize - 1).min(csize + CHUNK_SIZE - 1)
-                        );
-                        range
-                    }),
-                    &mut msg_tx
-                ).await
-            {
-                let mut error = err.to_string();
-                if let Some(src) = err.source() {
-                    error.push('\n');
-                    error.push_str(&src.to_string());
+                            range
+                        }),
+                        &mut msg_tx,
+                        hasher.as_mut(),
+                        true
+                    ).await
+                {
+                    return Ok(DownloadAction::from_error(&err, file.clone()));
                 }
-                return Ok(DownloadAction::Fail(error, file.clone()));
-            }
 
-            match temp_file.seek(SeekFrom::End(0)).await {
-                Ok(cursor) => {
-                    csize = cursor;
-                }
-                Err(err) => {
-                    let mut error = err.to_string();
-                    if let Some(src) = err.source() {
-                        error.push('\n');
-                        error.push_str(&src.to_string());
+                match temp_file.seek(SeekFrom::End(0)).await {
+                    Ok(cursor) => {
+                        csize = cursor;
+                    }
+                    Err(err) => {
+                        let mut error = err.to_string();
+                        if let Some(src) = err.source() {
+                            error.push('\n');
+                            error.push_str(&src.to_string());
+                        }
+                        return Ok(DownloadAction::Fail(error, file.clone()));
                     }
-                    return Ok(DownloadAction::Fail(error, file.clone()));
                 }
             }
         }
 
         Ok(
             if let Some(rhash) = file.get_hash() {
-                let lhash = file.hash(target).await?;
+                let lhash = match hasher {
+                    Some(hasher) => {
+                        let mut lhash = String::with_capacity(64);
+                        let _ = write!(lhash, "{:x}", hasher.finalize());
+                        lhash
+                    }
+                    None => file.hash(target).await?,
+                };
                 if rhash == lhash {
                     file.try_move(target).await?;
+
+                    if ARGUMENTS.dedup.is_some() {
+                        dedup::insert(rhash, file.to_pathbuf(target));
+                    }
+
                     DownloadAction::Complete(file.clone())
                 } else {
                     file.try_delete(target).await?;
@@ -308,6 +734,148 @@ impl PostFile {
         )
     }
 
+    // "{temp}.parts", tracks the progress of segmented downloads
+    fn to_parts_pathbuf(&self, target: &Target) -> PathBuf {
+        let mut path = self.to_temp_pathbuf(target).into_os_string();
+        path.push(".parts");
+        PathBuf::from(path)
+    }
+
+    // Interrupted segmented downloads are always resumed as such, while
+    // partial sequential downloads are continued sequentially.
+    async fn use_segments(&self, target: &Target, rsize: u64) -> Result<bool> {
+        if fs::try_exists(self.to_parts_pathbuf(target)).await? {
+            return Ok(true);
+        }
+
+        if ARGUMENTS.segments() == 1 || rsize < SEGMENT_MIN_SIZE {
+            return Ok(false);
+        }
+
+        Ok(
+            fs::metadata(self.to_temp_pathbuf(target)).await.map_or(true, |temp| temp.len() == 0)
+        )
+    }
+
+    // Downloads a file with multiple connections into a preallocated temp file.
+    // Segment progress is saved after every chunk, so downloads can be resumed.
+    async fn try_download_segmented(
+        &self,
+        target: &Target,
+        url: &str,
+        rsize: u64,
+        msg_tx: &UnboundedSender<DownloadAction>
+    ) -> Result<()> {
+        let temp = self.to_temp_pathbuf(target);
+        let parts_path = self.to_parts_pathbuf(target);
+
+        let segments = match fs::read_to_string(&parts_path).await {
+            Ok(text) if let Some(segments) = Segment::parse_all(&text, rsize) => segments,
+            _ => {
+                if let Some(parent) = temp.parent() {
+                    fs::create_dir_all(parent).await?;
+                }
+
+                File::options()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(&temp).await?
+                    .set_len(rsize).await?;
+
+                let segments = Segment::split(rsize, ARGUMENTS.segments());
+
+                fs::write(&parts_path, Segment::format_all(&segments, rsize)).await?;
+
+                segments
+            }
+        };
+
+        let count = segments.len();
+        let segments = Mutex::new(segments);
+
+        join_all(
+            (0..count).map(|index| {
+                self.try_download_segment(
+                    &temp,
+                    url,
+                    rsize,
+                    index,
+                    &segments,
+                    &parts_path,
+                    msg_tx.clone()
+                )
+            })
+        ).await
+            .into_iter()
+            .collect::<Result<Vec<()>>>()?;
+
+        fs::remove_file(&parts_path).await.with_context(|| {
+            let temp = self.get_local_temp();
+            let mut buf = String::with_capacity(24 + temp.len());
+            let _ = write!(buf, "delete segment state: {temp}.parts");
+            buf
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn try_download_segment(
+        &self,
+        temp: &Path,
+        url: &str,
+        rsize: u64,
+        index: usize,
+        segments: &Mutex<Vec<Segment>>,
+        parts_path: &Path,
+        mut msg_tx: UnboundedSender<DownloadAction>
+    ) -> Result<()> {
+        let mut file = File::options().write(true).open(temp).await?;
+
+        let mut range = String::with_capacity(32);
+
+        let mut stalls = 0;
+
+        loop {
+            let Segment { start, end, done } = segments.lock().await[index];
+
+            let offset = start + done;
+
+            if offset >= end {
+                return Ok(());
+            }
+
+            file.seek(SeekFrom::Start(offset)).await?;
+
+            range.clear();
+            let _ = write!(range, "bytes={offset}-{}", (end - 1).min(offset + CHUNK_SIZE - 1));
+
+            let written = self.try_download_range(
+                url,
+                &mut file,
+                &range,
+                &mut msg_tx,
+                None,
+                index == 0
+            ).await?;
+
+            if written == 0 {
+                stalls += 1;
+
+                if stalls == ARGUMENTS.max_tries {
+                    return Err(anyhow!("segment download stalled: {} ({range})", self.get_name()));
+                }
+            } else {
+                stalls = 0;
+            }
+
+            let mut segments = segments.lock().await;
+
+            segments[index].done = (done + written).min(end - start);
+
+            fs::write(parts_path, Segment::format_all(&segments, rsize)).await?;
+        }
+    }
+
     pub async fn try_fetch_remote_size_and_path(
         &self,
         target: &Target,
@@ -322,6 +890,10 @@ impl PostFile {
         let mut tries = 0;
 
         loop {
+            if shutdown::requested() {
+                return Err(Interrupted.into());
+            }
+
             tries += 1;
 
             let response = match CLIENT.head(&url).send().await {
@@ -330,14 +902,18 @@ impl PostFile {
                     return Err(anyhow!(err));
                 }
                 Err(_) => {
+                    retry::wait(ARGUMENTS.retry_delay, tries, None).await;
                     continue;
                 }
             };
 
             match response.status() {
                 status if status == StatusCode::OK => {
+                    // content_length() reports the (empty) body size of HEAD responses over HTTP/1.1
                     let size = response
-                        .content_length()
+                        .headers()
+                        .get(CONTENT_LENGTH)
+                        .and_then(|value| value.to_str().ok()?.parse().ok())
                         .map_or_else(
                             || size_error(status, "Content-Length header is not present", &url),
                             Ok
@@ -345,10 +921,20 @@ impl PostFile {
                     return Ok((size, response.url().to_string()));
                 }
                 StatusCode::FORBIDDEN | StatusCode::TOO_MANY_REQUESTS | StatusCode::NOT_FOUND => {
-                    try_wait(ARGUMENTS.rate_limit_backoff, msg_tx).await?;
+                    let delay = retry::backoff(
+                        ARGUMENTS.rate_limit_backoff,
+                        tries,
+                        retry::retry_after(response.headers())
+                    );
+                    try_wait(delay, msg_tx).await?;
                 }
                 status if status.is_server_error() => {
-                    try_wait(ARGUMENTS.server_error_delay, msg_tx).await?;
+                    let delay = retry::backoff(
+                        ARGUMENTS.server_error_delay,
+                        tries,
+                        retry::retry_after(response.headers())
+                    );
+                    try_wait(delay, msg_tx).await?;
                 }
                 status => {
                     size_error(status, "unexpected status code", &url)?;
@@ -357,13 +943,16 @@ impl PostFile {
         }
     }
 
+    // returns the number of bytes written, which is less than requested if the connection dropped
     async fn try_download_range(
         &self,
         url: &str,
         file: &mut File,
         range: &str,
-        msg_tx: &mut UnboundedSender<DownloadAction>
-    ) -> Result<()> {
+        msg_tx: &mut UnboundedSender<DownloadAction>,
+        mut hasher: Option<&mut Sha256>,
+        report_wait: bool
+    ) -> Result<u64> {
         fn download_error(status: StatusCode, message: &str, url: &str) -> Result<()> {
             Err(format_err!("[{status}] download failed: {message} ({url})"))
         }
@@ -371,6 +960,10 @@ impl PostFile {
         let mut tries = 0;
 
         loop {
+            if shutdown::requested() {
+                return Err(Interrupted.into());
+            }
+
             tries += 1;
 
             let response = match CLIENT.get(url).header("Range", range).send().await {
@@ -379,6 +972,7 @@ impl PostFile {
                     return Err(anyhow!(err));
                 }
                 Err(_) => {
+                    retry::wait(ARGUMENTS.retry_delay, tries, None).await;
                     continue;
                 }
             };
@@ -386,29 +980,45 @@ impl PostFile {
             match response.status() {
                 StatusCode::PARTIAL_CONTENT => {
                     let mut stream = response.bytes_stream();
+                    let mut written = 0;
 
                     while let Some(Ok(bytes)) = stream.next().await {
+                        limit::consume(bytes.len()).await;
                         msg_tx.send(DownloadAction::ReportSize(bytes.len() as u64))?;
                         if let Err(err) = file.write_all(&bytes).await {
-                            msg_tx.send({
-                                let error = err.to_string();
-                                let mut buf = String::with_capacity(
-                                    13 + self.name_range.len() + 1 + error.len()
-                                );
-                                let _ = write!(buf, "write error: {}\n{error}", self.get_name());
-                                DownloadAction::Panic(buf)
-                            })?;
-                            exit(1);
+                            // e.g. a full disk, which affects all other downloads as well
+                            shutdown::request();
+
+                            return Err(err).with_context(|| {
+                                let name = self.get_name();
+                                let mut buf = String::with_capacity(13 + name.len());
+                                let _ = write!(buf, "write error: {name}");
+                                buf
+                            });
                         }
+                        if let Some(hasher) = hasher.as_mut() {
+                            hasher.update(&bytes);
+                        }
+                        written += bytes.len() as u64;
                     }
                     file.flush().await?;
-                    break Ok(());
+                    break Ok(written);
                 }
                 StatusCode::FORBIDDEN | StatusCode::TOO_MANY_REQUESTS | StatusCode::NOT_FOUND => {
-                    try_wait(ARGUMENTS.rate_limit_backoff, msg_tx).await?;
+                    let delay = retry::backoff(
+                        ARGUMENTS.rate_limit_backoff,
+                        tries,
+                        retry::retry_after(response.headers())
+                    );
+                    try_wait_or_sleep(delay, msg_tx, report_wait).await?;
                 }
                 status if status.is_server_error() => {
-                    try_wait(ARGUMENTS.server_error_delay, msg_tx).await?;
+                    let delay = retry::backoff(
+                        ARGUMENTS.server_error_delay,
+                        tries,
+                        retry::retry_after(response.headers())
+                    );
+                    try_wait_or_sleep(delay, msg_tx, report_wait).await?;
                 }
                 status => {
                     download_error(status, "unexpected status code", url)?;
@@ -420,7 +1030,90 @@ impl PostFile {
 
 async fn try_wait(duration: Duration, msg_tx: &mut UnboundedSender<DownloadAction>) -> Result<()> {
     msg_tx.send(DownloadAction::Wait)?;
-    sleep(duration).await;
+    shutdown::sleep(duration).await;
     msg_tx.send(DownloadAction::Continue)?;
     Ok(())
 }
+
+// segments of a file share its active/waiting state, so only the first one reports it
+async fn try_wait_or_sleep(
+    duration: Duration,
+    msg_tx: &mut UnboundedSender<DownloadAction>,
+    report_wait: bool
+) -> Result<()> {
+    if report_wait {
+        try_wait(duration, msg_tx).await
+    } else {
+        shutdown::sleep(duration).await;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Segment {
+    start: u64,
+    // exclusive
+    end: u64,
+    done: u64,
+}
+
+impl Segment {
+    fn split(size: u64, count: usize) -> Vec<Self> {
+        let len = size.div_ceil(count as u64);
+
+        (0..size)
+            .step_by(len.try_into().unwrap_or(usize::MAX))
+            .map(|start| Self { start, end: (start + len).min(size), done: 0 })
+            .collect()
+    }
+
+    // "{start} {end} {done}" per line, preceded by the file size
+    fn parse_all(text: &str, size: u64) -> Option<Vec<Self>> {
+        let mut lines = text.lines();
+
+        if lines.next()?.parse::<u64>().ok()? != size {
+            return None;
+        }
+
+        let mut segments = Vec::new();
+
+        for line in lines {
+            let mut values = line.split(' ').map(str::parse::<u64>);
+
+            let (Some(Ok(start)), Some(Ok(end)), Some(Ok(done)), None) = (
+                values.next(),
+                values.next(),
+                values.next(),
+                values.next(),
+            ) else {
+                return None;
+            };
+
+            segments.push(Self { start, end, done: done.min(end.saturating_sub(start)) });
+        }
+
+        // segments must cover the whole file without gaps
+        let mut offset = 0;
+
+        for segment in &segments {
+            if segment.start != offset || segment.end <= segment.start {
+                return None;
+            }
+            offset = segment.end;
+        }
+
+        (offset == size).then_some(segments)
+    }
+
+    fn format_all(segments: &[Self], size: u64) -> String {
+        let mut buf = String::with_capacity(20 + segments.len() * 60);
+
+        let _ = writeln!(buf, "{size}");
+
+        for Self { start, end, done } in segments {
+            let _ = writeln!(buf, "{start} {end} {done}");
+        }
+
+        buf
+    }
+}
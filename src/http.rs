@@ -1,40 +1,95 @@
-use crate::cli::ARGUMENTS;
+use crate::{ cli::ARGUMENTS, post::PostError, retry, target::Site };
 use anyhow::{ Result, anyhow };
-use reqwest::{ Client, ClientBuilder, Proxy, header::{ HeaderMap, HeaderValue }, redirect::Policy };
+use reqwest::{
+    Client,
+    ClientBuilder,
+    Proxy,
+    RequestBuilder,
+    StatusCode,
+    header::{ HeaderMap, HeaderValue },
+    redirect::Policy,
+};
 use serde::Deserialize;
 use serde_json::json;
 use std::{ fmt::Write, process::exit, sync::LazyLock };
 
 static VERSION: &str = concat!("kumono ", env!("CARGO_PKG_VERSION"));
 
-pub static CLIENT: LazyLock<Client> = LazyLock::new(|| {
-    let build_client = || -> Result<Client> {
-        let mut headers = HeaderMap::new();
-        headers.insert("accept", HeaderValue::from_static("text/css"));
-
-        let mut client = ClientBuilder::new()
-            .default_headers(headers)
-            .user_agent(VERSION)
-            .cookie_store(true)
-            .connect_timeout(ARGUMENTS.connect_timeout)
-            .timeout(ARGUMENTS.read_timeout)
-            .redirect(Policy::limited(1))
-            .https_only(true)
-            .http2_prior_knowledge();
-
-        if let Some(proxy) = &ARGUMENTS.proxy {
-            client = client.proxy(Proxy::all(proxy)?);
-        }
-
-        Ok(client.build()?)
+pub static CLIENT: LazyLock<Clients> = LazyLock::new(|| {
+    let build_clients = || -> Result<Clients> {
+        // plain HTTP is only allowed for sites whose base URL was overridden to use it
+        // (e.g. a mock server), the other site keeps enforcing HTTPS
+        let http = if
+            [Site::Coomer, Site::Kemono]
+                .into_iter()
+                .any(|site| !site.base_url().starts_with("https://"))
+        {
+            Some(try_build_client(false)?)
+        } else {
+            None
+        };
+
+        Ok(Clients { https: try_build_client(true)?, http })
     };
 
-    build_client().unwrap_or_else(|err| {
+    build_clients().unwrap_or_else(|err| {
         eprintln!("{err}");
         exit(2);
     })
 });
 
+fn try_build_client(https: bool) -> Result<Client> {
+    let mut headers = HeaderMap::new();
+    headers.insert("accept", HeaderValue::from_static("text/css"));
+
+    let mut client = ClientBuilder::new()
+        .default_headers(headers)
+        .user_agent(VERSION)
+        .cookie_store(true)
+        .connect_timeout(ARGUMENTS.connect_timeout)
+        .timeout(ARGUMENTS.read_timeout)
+        .redirect(Policy::limited(1))
+        .https_only(https);
+
+    if https {
+        client = client.http2_prior_knowledge();
+    }
+
+    if let Some(proxy) = &ARGUMENTS.proxy {
+        client = client.proxy(Proxy::all(proxy)?);
+    }
+
+    Ok(client.build()?)
+}
+
+// requests are sent by the client matching the scheme of their URL, which is
+// always built from a site's base URL
+pub struct Clients {
+    https: Client,
+    http: Option<Client>,
+}
+
+impl Clients {
+    fn pick(&self, url: &str) -> &Client {
+        match &self.http {
+            Some(http) if !url.starts_with("https://") => http,
+            _ => &self.https,
+        }
+    }
+
+    pub fn get(&self, url: &str) -> RequestBuilder {
+        self.pick(url).get(url)
+    }
+
+    pub fn head(&self, url: &str) -> RequestBuilder {
+        self.pick(url).head(url)
+    }
+
+    pub fn post(&self, url: &str) -> RequestBuilder {
+        self.pick(url).post(url)
+    }
+}
+
 #[allow(unused)]
 #[derive(Deserialize)]
 #[serde(untagged)]
@@ -58,7 +113,7 @@ pub async fn try_login() -> Result<()> {
         let Some(user) = ARGUMENTS.coomer_user.as_ref() &&
         let Some(pass) = ARGUMENTS.coomer_pass.as_ref()
     {
-        Some(("coomer.st", user, pass))
+        Some((Site::Coomer.base_url(), user, pass))
     } else {
         None
     };
@@ -67,25 +122,26 @@ pub async fn try_login() -> Result<()> {
         let Some(user) = ARGUMENTS.kemono_user.as_ref() &&
         let Some(pass) = ARGUMENTS.kemono_pass.as_ref()
     {
-        Some(("kemono.cr", user, pass))
+        Some((Site::Kemono.base_url(), user, pass))
     } else {
         None
     };
 
     // add validation: non-zero length, charset?
 
-    for (host, user, pass) in [coomer_auth, kemono_auth].into_iter().flatten() {
-        let mut url = String::with_capacity(8 + host.len() + 28);
-        let _ = write!(url, "https://{host}/api/v1/authentication/login");
+    for (base, user, pass) in [coomer_auth, kemono_auth].into_iter().flatten() {
+        let mut url = String::with_capacity(base.len() + 28);
+        let _ = write!(url, "{base}/api/v1/authentication/login");
 
         let json = json!({"username": user, "password": pass});
 
-        let mut tries = 0;
+        let mut retries = 0;
 
         loop {
-            tries += 1;
-
-            match CLIENT.post(&url).json(&json).send().await {
+            let err = match CLIENT.post(&url).json(&json).send().await {
+                Ok(res) if is_retryable(res.status()) => {
+                    PostError::Status(res.status(), retry::retry_after(res.headers()))
+                }
                 Ok(res) =>
                     match res.json().await? {
                         LoginResponse::Success { .. } => {
@@ -95,15 +151,19 @@ pub async fn try_login() -> Result<()> {
                             return Err(anyhow!(error));
                         }
                     }
+                Err(err) => PostError::Connect(err.to_string()),
+            };
 
-                Err(err) => {
-                    if tries == ARGUMENTS.max_tries {
-                        return Err(anyhow!(err));
-                    }
-                }
-            }
+            err.try_interpret(retries).await?;
+            retries += 1;
         }
     }
 
     Ok(())
 }
+
+// rate limiting and server errors, the response body is not a login response
+fn is_retryable(status: StatusCode) -> bool {
+    matches!(status, StatusCode::FORBIDDEN | StatusCode::TOO_MANY_REQUESTS) ||
+        status.is_server_error()
+}
@@ -0,0 +1,59 @@
+use crate::cli::ARGUMENTS;
+use std::sync::LazyLock;
+use tokio::{ sync::Mutex, time::{ Duration, Instant, sleep } };
+
+static LIMITER: LazyLock<Option<RateLimiter>> = LazyLock::new(||
+    ARGUMENTS.limit_rate.map(RateLimiter::new)
+);
+
+// Token bucket shared by all downloads, holding up to one second worth of bytes.
+// Tokens may go negative, so concurrent consumers queue up behind each other
+// instead of all waking up at once.
+struct RateLimiter {
+    rate: f64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl RateLimiter {
+    #[allow(clippy::cast_precision_loss)]
+    fn new(rate: u64) -> Self {
+        Self {
+            rate: rate as f64,
+            bucket: Mutex::new(Bucket {
+                tokens: rate as f64,
+                refilled: Instant::now(),
+            }),
+        }
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    async fn consume(&self, bytes: usize) {
+        let deficit = {
+            let mut bucket = self.bucket.lock().await;
+
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+
+            bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.rate) - bytes as f64;
+            bucket.refilled = now;
+
+            -bucket.tokens
+        };
+
+        if deficit > 0.0 {
+            sleep(Duration::from_secs_f64(deficit / self.rate)).await;
+        }
+    }
+}
+
+// waits until the given amount of bytes may be downloaded (--limit-rate)
+pub async fn consume(bytes: usize) {
+    if let Some(limiter) = LIMITER.as_ref() {
+        limiter.consume(bytes).await;
+    }
+}
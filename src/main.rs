@@ -1,8 +1,15 @@
-use crate::{ cli::ARGUMENTS, file::PostFile, progress::DownloadAction, target::Target };
-use anyhow::Result;
+use crate::{
+    cli::ARGUMENTS,
+    concurrency::Concurrency,
+    file::PostFile,
+    progress::DownloadAction,
+    target::Target,
+};
+use anyhow::{ Result, anyhow };
 use futures::future::join_all;
 use itertools::Itertools;
 use std::{
+    collections::HashSet,
     path::PathBuf,
     process::exit,
     sync::{ Arc, atomic::Ordering::Relaxed },
@@ -10,17 +17,27 @@ use std::{
     time::Duration,
 };
 use strum_macros::Display;
-use tokio::{ fs, sync::{ Semaphore, mpsc }, task, time::sleep };
+use tokio::{ fs, sync::mpsc, task };
 
 mod cli;
+mod concurrency;
+mod content;
+mod dedup;
 mod ext;
 mod file;
 mod http;
+mod limit;
+mod plan;
 mod post;
 mod pretty;
 mod profile;
 mod progress;
+mod prune;
+mod retry;
+mod shutdown;
 mod target;
+mod template;
+mod verify;
 
 #[allow(clippy::too_many_lines)]
 #[tokio::main]
@@ -29,10 +46,20 @@ async fn main() -> Result<()> {
         eprintln!("{}", *ARGUMENTS);
     }
 
-    if ARGUMENTS.download_archive {
+    if
+        (ARGUMENTS.download_archive || ARGUMENTS.update.is_some()) &&
+        !ARGUMENTS.is_dry_run() &&
+        ARGUMENTS.verify.is_none()
+    {
         fs::create_dir_all(PathBuf::from_iter([&ARGUMENTS.output_path, "db"])).await?;
     }
 
+    if ARGUMENTS.dedup.is_some() {
+        dedup::try_build_index()?;
+    }
+
+    shutdown::try_listen()?;
+
     http::try_login().await?;
 
     let mut targets = Vec::new();
@@ -48,12 +75,43 @@ async fn main() -> Result<()> {
 
     let total_targets = targets.len();
 
+    // shared by all targets, so an adapted limit carries over to the next target
+    let concurrency = Concurrency::new(ARGUMENTS.threads());
+
     for (i, target) in targets.into_iter().enumerate() {
+        if shutdown::requested() {
+            eprintln!("Stopped, skipping {}", pretty::with_word(total_targets - i, "target"));
+            break;
+        }
+
         let target = Arc::new(target);
 
-        let mut files = profile::try_get_files(target.clone(), i + 1).await?;
+        if let Some(action) = ARGUMENTS.verify {
+            verify::try_verify(&target, action).await?;
+
+            if i != total_targets - 1 {
+                eprintln!();
+            }
+            continue;
+        }
+
+        let (mut files, mut new_post_ids, complete) = tokio::select! {
+            biased;
+            () = shutdown::wait() => {
+                eprintln!("\nStopped, skipping {}", pretty::with_word(total_targets - i, "target"));
+                break;
+            }
+            result = profile::try_get_files(target.clone(), i + 1) => result?,
+        };
+
+        // compared to all remote files, regardless of extension filters
+        if let Some(action) = ARGUMENTS.prune && !ARGUMENTS.list_extensions {
+            prune::try_prune(&target, &files, complete, action)?;
+        }
 
         if files.is_empty() {
+            target.try_write_seen(&new_post_ids)?;
+
             if i != total_targets - 1 {
                 eprintln!();
             }
@@ -71,15 +129,30 @@ async fn main() -> Result<()> {
 
         let mut total = files.len();
 
+        // posts with filtered files are not marked as seen, so a later update run
+        // without (or with other) filters still downloads them
+        let mut filtered_post_ids = HashSet::new();
+
+        let mut filter = |file: &Arc<PostFile>, keep: bool| {
+            if !keep && let Some(id) = &file.get_post().id {
+                filtered_post_ids.insert(id.clone());
+            }
+            keep
+        };
+
         if let Some(exts) = ARGUMENTS.included() {
-            files.retain(|file| file.get_ext().is_some_and(|ext| exts.contains(ext)));
+            files.retain(|file| filter(file, file.get_ext().is_some_and(|ext| exts.contains(ext))));
             files_left_msg(Filter::Inclusive, total, files.len());
         } else if let Some(exts) = ARGUMENTS.excluded() {
-            files.retain(|file| file.get_ext().is_none_or(|ext| !exts.contains(ext)));
+            files.retain(|file| filter(file, file.get_ext().is_none_or(|ext| !exts.contains(ext))));
             files_left_msg(Filter::Exclusive, total, files.len());
         }
 
+        new_post_ids.retain(|id| !filtered_post_ids.contains(id));
+
         if files.is_empty() {
+            target.try_write_seen(&new_post_ids)?;
+
             if i != total_targets - 1 {
                 eprintln!();
             }
@@ -101,6 +174,26 @@ async fn main() -> Result<()> {
         }
 
         if files.is_empty() {
+            target.try_write_seen(&new_post_ids)?;
+
+            if i != total_targets - 1 {
+                eprintln!();
+            }
+            continue;
+        }
+
+        if let Some(format) = ARGUMENTS.export_urls {
+            plan::try_export(&target, &files, format)?;
+
+            if i != total_targets - 1 {
+                eprintln!();
+            }
+            continue;
+        }
+
+        if ARGUMENTS.dry_run.is_some() {
+            plan::try_print(&target, &files).await?;
+
             if i != total_targets - 1 {
                 eprintln!();
             }
@@ -109,37 +202,53 @@ async fn main() -> Result<()> {
 
         let left = files.len();
 
-        fs::create_dir_all(target.as_pathbuf()).await?;
+        if ARGUMENTS.path_template.is_none() {
+            fs::create_dir_all(target.as_pathbuf()).await?;
+        }
 
-        let archive_path = target.as_archive_pathbuf().clone();
+        let archive_file = if ARGUMENTS.download_archive {
+            Some(progress::try_open_archive(target.as_archive_pathbuf())?)
+        } else {
+            None
+        };
 
         let (msg_tx, msg_rx) = mpsc::unbounded_channel::<DownloadAction>();
 
         let files_by_type = ext::count(&files);
 
+        let progress = {
+            let concurrency = concurrency.clone();
+
+            thread::spawn(move || {
+                progress::progress_bar(
+                    left,
+                    archive_file,
+                    msg_rx,
+                    i == total_targets - 1,
+                    files_by_type,
+                    &concurrency
+                )
+            })
+        };
+
+        // doesn't keep the channel open, so the bar finishes with the downloads
+        let tx = msg_tx.downgrade();
         thread::spawn(move || {
-            progress::progress_bar(
-                left,
-                archive_path,
-                msg_rx,
-                i == total_targets - 1,
-                files_by_type
-            );
-        });
-
-        let tx = msg_tx.clone();
-        thread::spawn(move || {
-            while tx.send(DownloadAction::Update).is_ok() {
+            while tx.upgrade().is_some_and(|tx| tx.send(DownloadAction::Update).is_ok()) {
                 thread::sleep(Duration::from_secs(1));
             }
         });
 
         let mut tasks = Vec::with_capacity(files.len());
 
-        let sem = Arc::new(Semaphore::new(ARGUMENTS.threads()));
-
         for file in files {
-            let permit = sem.clone().acquire_owned().await?;
+            let permit = tokio::select! {
+                biased;
+                () = shutdown::wait() => {
+                    break;
+                }
+                permit = concurrency.acquire() => permit?,
+            };
             let msg_tx = msg_tx.clone();
             let target = target.clone();
 
@@ -147,33 +256,57 @@ async fn main() -> Result<()> {
                 task::spawn(async move {
                     let _permit = permit;
 
-                    match PostFile::try_download(file.clone(), &target, msg_tx.clone()).await {
-                        Ok(action) => msg_tx.send(action).unwrap(),
-                        Err(err) => {
-                            let mut error = err.to_string();
+                    let action = match
+                        PostFile::try_download(file.clone(), &target, msg_tx.clone()).await
+                    {
+                        Ok(action) => action,
+                        Err(err) => DownloadAction::from_error(&err, file),
+                    };
 
-                            if let Some(source) = err.source() {
-                                error.push('\n');
-                                error.push_str(&source.to_string());
-                            }
+                    let success = matches!(
+                        action,
+                        DownloadAction::Complete(_) |
+                            DownloadAction::Skip(_) |
+                            DownloadAction::Link(_)
+                    );
 
-                            msg_tx.send(DownloadAction::Fail(error, file)).unwrap();
-                        }
-                    }
+                    msg_tx.send(action).unwrap();
+
+                    success
                 })
             );
         }
 
-        join_all(tasks).await;
+        // posts are only marked as seen once all of their files are present,
+        // so failed (or never started) downloads are retried by the next update run
+        if
+            join_all(tasks).await.into_iter().all(|task| task.is_ok_and(|success| success)) &&
+            !shutdown::requested()
+        {
+            target.try_write_seen(&new_post_ids)?;
+        }
+
+        drop(msg_tx);
 
-        // wait for the bar to (hopefully) finish properly
-        sleep(Duration::from_millis((left / 10).try_into().unwrap_or_default())).await;
+        progress.join().map_err(|_| anyhow!("progress bar thread panicked"))??;
+    }
+
+    if shutdown::requested() {
+        eprintln!("\nStopped early, partial downloads are resumed by the next run.");
     }
 
     if progress::DOWNLOADS_FAILED.load(Relaxed) {
         exit(4);
     }
 
+    if verify::VERIFY_FAILED.load(Relaxed) {
+        exit(8);
+    }
+
+    if shutdown::requested() {
+        exit(130);
+    }
+
     Ok(())
 }
 
@@ -0,0 +1,206 @@
+use crate::{
+    cli::{ ARGUMENTS, DryRun, ExportFormat },
+    dedup,
+    file::PostFile,
+    pretty,
+    target::Target,
+};
+use anyhow::Result;
+use futures::{ StreamExt, stream };
+use indicatif::HumanBytes;
+use itertools::Itertools;
+use chrono::NaiveDateTime;
+use serde::Serialize;
+use std::{ collections::HashSet, fmt::Write, io::{ self, Write as ioWrite }, sync::Arc };
+use tokio::{ fs, sync::mpsc };
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Download,
+    Resume,
+    Link,
+    Skip,
+}
+
+impl Status {
+    fn as_str(self) -> &'static str {
+        match self {
+            Status::Download => "download",
+            Status::Resume => "resume",
+            Status::Link => "link",
+            Status::Skip => "skip",
+        }
+    }
+}
+
+// Prints "{status}\t{url}\t{path}[\t{size}]" for every file (--dry-run), sorted by path.
+// Files are skipped if they exist, linked if a copy exists elsewhere (--dedup),
+// and resumed if a temporary file exists.
+pub async fn try_print(target: &Target, files: &HashSet<Arc<PostFile>>) -> Result<()> {
+    let files: Vec<&Arc<PostFile>> = files
+        .iter()
+        .sorted_by_cached_key(|file| file.to_pathbuf(target))
+        .collect();
+
+    let plans: Vec<Result<(Status, Option<u64>)>> = stream
+        ::iter(files.iter().map(|file| try_plan(target, file)))
+        .buffered(ARGUMENTS.threads())
+        .collect().await;
+
+    let (mut downloads, mut links, mut skipped, mut size) = (0, 0, 0, 0);
+
+    let mut line = String::new();
+
+    for (file, plan) in files.into_iter().zip(plans) {
+        let (status, rsize) = plan?;
+
+        line.clear();
+
+        let _ = write!(
+            line,
+            "{}\t{}\t{}",
+            status.as_str(),
+            file.to_url(target),
+            file.to_pathbuf(target).display()
+        );
+
+        if let Some(rsize) = rsize {
+            let _ = write!(line, "\t{rsize}");
+            size += rsize;
+        }
+
+        println!("{line}");
+
+        match status {
+            Status::Skip => skipped += 1,
+            Status::Link => links += 1,
+            Status::Download | Status::Resume => downloads += 1,
+        }
+    }
+
+    let mut summary = String::with_capacity(64);
+
+    let _ = write!(
+        summary,
+        "dry run: would download {}",
+        pretty::with_word(downloads, "file")
+    );
+
+    if ARGUMENTS.dry_run == Some(DryRun::Size) {
+        let _ = write!(summary, " ({})", HumanBytes(size));
+    }
+
+    if ARGUMENTS.dedup.is_some() {
+        let _ = write!(summary, ", link {}", pretty::with_word(links, "file"));
+    }
+
+    let _ = write!(summary, ", skip {} (existing)", pretty::with_word(skipped, "file"));
+
+    eprintln!("{summary}");
+
+    Ok(())
+}
+
+// a line of --export-urls jsonl
+#[derive(Serialize)]
+struct ExportLine<'a> {
+    url: &'a str,
+    path: &'a str,
+    hash: Option<&'a str>,
+    post_id: Option<&'a str>,
+    post_title: Option<&'a str>,
+    published: Option<NaiveDateTime>,
+}
+
+// Prints the files of a target for other downloaders (--export-urls), sorted by path.
+pub fn try_export(
+    target: &Target,
+    files: &HashSet<Arc<PostFile>>,
+    format: ExportFormat
+) -> Result<()> {
+    let mut stdout = io::stdout().lock();
+
+    let mut buf = String::new();
+
+    for (path, file) in files
+        .iter()
+        .map(|file| (file.to_pathbuf(target), file))
+        .sorted_by(|(a, _), (b, _)| a.cmp(b))
+    {
+        let url = file.to_url(target);
+
+        buf.clear();
+
+        match format {
+            ExportFormat::Aria2 => {
+                let _ = writeln!(buf, "{url}");
+
+                if let Some(dir) = path.parent() {
+                    let _ = writeln!(buf, "  dir={}", dir.display());
+                }
+
+                if let Some(name) = path.file_name() {
+                    let _ = writeln!(buf, "  out={}", name.display());
+                }
+
+                if let Some(hash) = file.get_hash() {
+                    let _ = writeln!(buf, "  checksum=sha-256={hash}");
+                }
+            }
+            ExportFormat::Plain => {
+                let _ = writeln!(buf, "{url}");
+            }
+            ExportFormat::Jsonl => {
+                let post = file.get_post();
+
+                buf.push_str(
+                    &serde_json::to_string(
+                        &(ExportLine {
+                            url: &url,
+                            path: &path.to_string_lossy(),
+                            hash: file.get_hash(),
+                            post_id: post.id.as_deref(),
+                            post_title: post.title.as_deref(),
+                            published: post.published,
+                        })
+                    )?
+                );
+                buf.push('\n');
+            }
+        }
+
+        stdout.write_all(buf.as_bytes())?;
+    }
+
+    stdout.flush()?;
+
+    eprintln!("exported {}", pretty::with_word(files.len(), "file"));
+
+    Ok(())
+}
+
+async fn try_plan(target: &Target, file: &PostFile) -> Result<(Status, Option<u64>)> {
+    if file.try_exists(target).await? {
+        return Ok((Status::Skip, None));
+    }
+
+    if ARGUMENTS.dedup.is_some() && file.get_hash().and_then(dedup::find).is_some() {
+        return Ok((Status::Link, None));
+    }
+
+    let status = if fs::try_exists(file.to_temp_pathbuf(target)).await? {
+        Status::Resume
+    } else {
+        Status::Download
+    };
+
+    let size = if ARGUMENTS.dry_run == Some(DryRun::Size) {
+        // wait notifications are meant for the progress bar, which doesn't exist here
+        let (mut msg_tx, _msg_rx) = mpsc::unbounded_channel();
+        Some(file.try_fetch_remote_size_and_path(target, &mut msg_tx).await?.0)
+    } else {
+        None
+    };
+
+    Ok((status, size))
+}
@@ -1,14 +1,28 @@
-use crate::{ cli::ARGUMENTS, file::{ PostFile, PostFileRaw }, http::CLIENT };
+use crate::{
+    cli::ARGUMENTS,
+    file::{ PostFile, PostFileRaw, sanitize, truncate },
+    http::CLIENT,
+    retry,
+};
 use anyhow::{ Result, format_err };
+use chrono::{ DateTime, NaiveDateTime };
 use regex::Regex;
 use reqwest::StatusCode;
-use serde::{ Deserialize, de::DeserializeOwned };
-use std::{ mem, sync::{ Arc, LazyLock } };
+use serde::{ Deserialize, Deserializer, de::{ self, DeserializeOwned } };
+use serde_json::Value;
+use std::{ fmt::Write, mem, sync::{ Arc, LazyLock } };
 use thiserror::Error;
 use tokio::time::{ Duration, sleep };
 
 const API_DELAY: Duration = Duration::from_millis(100);
 
+// Mega, Google Drive, Dropbox and Pixeldrain links, up to the end of the attribute/line
+static RE_EXTERNAL: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r#"https?://(?:[a-z0-9-]+\.)?(?:mega\.(?:nz|co\.nz|io)|drive\.google\.com|docs\.google\.com|dropbox\.com|dropboxusercontent\.com|pixeldrain\.com)(?:/[^\s"'<>]*)?"#
+    ).unwrap()
+});
+
 static RE_OUT_OF_BOUNDS: LazyLock<Regex> = LazyLock::new(|| {
     Regex::new(r#"\{"error":"Offset [0-9]+ is bigger than total count [0-9]+\."\}"#).unwrap()
 });
@@ -21,6 +35,7 @@ pub async fn try_fetch<D: DeserializeOwned>(url: &str) -> Result<D, PostError> {
         .map_err(|err| PostError::Connect(err.to_string()))?;
 
     let status = res.status();
+    let retry_after = retry::retry_after(res.headers());
 
     let Ok(text) = res.text().await else {
         eprintln!("skipping page due to malformed response (server issue)");
@@ -30,29 +45,248 @@ pub async fn try_fetch<D: DeserializeOwned>(url: &str) -> Result<D, PostError> {
     if status == StatusCode::BAD_REQUEST && RE_OUT_OF_BOUNDS.is_match(&text) {
         Ok(serde_json::from_str("[]").unwrap())
     } else if status != StatusCode::OK {
-        Err(PostError::Status(status))
+        Err(PostError::Status(status, retry_after))
     } else {
         serde_json::from_str(&text).map_err(|err| PostError::MalformedPost(err.to_string()))
     }
 }
 
+// API requests outside of pages (profiles, single posts, favorites), retried like pages
+pub async fn try_fetch_retrying<D: DeserializeOwned>(url: &str) -> Result<D> {
+    let mut retries = 0;
+
+    loop {
+        match try_fetch(url).await {
+            Ok(data) => {
+                return Ok(data);
+            }
+            Err(PostError::MalformedPage) => {
+                return Err(format_err!("malformed response: {url}"));
+            }
+            Err(err) => {
+                err.try_interpret(retries).await?;
+                retries += 1;
+            }
+        }
+    }
+}
+
 pub trait Post {
     fn files(&mut self) -> Vec<Arc<PostFile>>;
+
+    fn published(&self) -> Option<NaiveDateTime>;
+
+    fn id(&self) -> Option<&str>;
+
+    fn title(&self) -> Option<&str> {
+        None
+    }
+
+    // HTML text of the post
+    fn content(&self) -> Option<&str> {
+        None
+    }
+
+    // external download links in the content of the post
+    fn links(&self) -> Vec<String> {
+        extract_links(self.content().into_iter())
+    }
+
+    // the API object of the post, only kept with --write-json
+    fn raw(&self) -> Option<&Value> {
+        None
+    }
+
+    fn meta(&self) -> Arc<PostMeta> {
+        Arc::new(PostMeta {
+            id: self.id().map(ToString::to_string),
+            title: self.title().map(ToString::to_string),
+            published: self.published(),
+        })
+    }
+}
+
+// post information every file keeps a reference to, used for local paths
+#[derive(Default, Debug)]
+pub struct PostMeta {
+    pub id: Option<String>,
+    pub title: Option<String>,
+    pub published: Option<NaiveDateTime>,
+}
+
+impl PostMeta {
+    // "{post_id} - {post_title}", or "{post_id}" for posts without a title
+    pub fn to_dir_name(&self) -> Option<String> {
+        let id = self.id.as_deref()?;
+
+        let dir = match self.title.as_deref().map(str::trim) {
+            Some(title) if !title.is_empty() => {
+                let title = truncate(title, 128);
+                let mut dir = String::with_capacity(id.len() + 3 + title.len());
+                let _ = write!(dir, "{id} - {title}");
+                dir
+            }
+            _ => id.to_string(),
+        };
+
+        Some(sanitize(&dir))
+    }
+}
+
+// Wraps a post to keep its API object for metadata sidecars (--write-json).
+// Without the option, posts are deserialized directly and nothing is kept.
+pub struct WithRaw<P> {
+    post: P,
+    raw: Option<Value>,
+}
+
+impl<'de, P: DeserializeOwned> Deserialize<'de> for WithRaw<P> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if ARGUMENTS.write_json {
+            let mut raw = Value::deserialize(deserializer)?;
+            let post = P::deserialize(&raw).map_err(de::Error::custom)?;
+
+            // single post responses wrap the post object
+            if let Some(inner) = raw.get_mut("post") && inner.is_object() {
+                raw = inner.take();
+            }
+
+            Ok(Self { post, raw: Some(raw) })
+        } else {
+            Ok(Self { post: P::deserialize(deserializer)?, raw: None })
+        }
+    }
+}
+
+impl<P: Post> Post for WithRaw<P> {
+    fn files(&mut self) -> Vec<Arc<PostFile>> {
+        self.post.files()
+    }
+
+    fn published(&self) -> Option<NaiveDateTime> {
+        self.post.published()
+    }
+
+    fn id(&self) -> Option<&str> {
+        self.post.id()
+    }
+
+    fn title(&self) -> Option<&str> {
+        self.post.title()
+    }
+
+    fn content(&self) -> Option<&str> {
+        self.post.content()
+    }
+
+    fn links(&self) -> Vec<String> {
+        self.post.links()
+    }
+
+    fn raw(&self) -> Option<&Value> {
+        self.raw.as_ref()
+    }
+}
+
+// "/data/ab/cd/{hash}.{ext}" references in post content, optionally absolute, thumbnails
+// and/or with a query. Also rewritten in content files, so both agree on the post's files.
+pub static RE_INLINE: LazyLock<Regex> = LazyLock::new(||
+    Regex::new(
+        r#"(?<attr>(?:src|href)\s*=\s*["'])(?:https?://[^/"']+)?(?:/thumbnail)?/data(?<path>/[0-9a-f]{2}/[0-9a-f]{2}/[0-9a-f]{64}\.[[:alnum:]]+)(?:\?[^"']*)?"#
+    ).unwrap()
+);
+
+// the main file comes first, matching the order on the site (index starts at 1),
+// files that are only embedded in the content come last
+fn collect_files(
+    post: &Arc<PostMeta>,
+    file: Option<PostFileRaw>,
+    attachments: Vec<PostFileRaw>,
+    content: Option<&str>
+) -> Vec<Arc<PostFile>> {
+    let mut post_files: Vec<Arc<PostFile>> = Vec::with_capacity(attachments.len() + 1);
+
+    for raw in file.into_iter().chain(attachments) {
+        if let Some(path) = raw.path {
+            post_files.push(PostFile::new(path, raw.name, post.clone(), post_files.len() + 1));
+        }
+    }
+
+    for caps in content.into_iter().flat_map(|content| RE_INLINE.captures_iter(content)) {
+        let path = &caps["path"];
+
+        // thumbnails share the hash (and path) of the full size file
+        if post_files.iter().all(|file| file.get_path() != path) {
+            post_files.push(
+                PostFile::new(path.to_string(), None, post.clone(), post_files.len() + 1)
+            );
+        }
+    }
+
+    post_files
+}
+
+// unique links in order of appearance, without HTML escapes and trailing punctuation
+fn extract_links<'a>(texts: impl Iterator<Item = &'a str>) -> Vec<String> {
+    let mut links: Vec<String> = Vec::new();
+
+    for text in texts {
+        for link in RE_EXTERNAL.find_iter(text) {
+            let link = link
+                .as_str()
+                .trim_end_matches(['.', ',', ';', ':', '!', '?', ')', ']'])
+                .replace("&amp;", "&");
+
+            if !links.contains(&link) {
+                links.push(link);
+            }
+        }
+    }
+
+    links
+}
+
+// fancards use numeric IDs, everything else uses strings
+fn deserialize_id<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    Ok(
+        Option::<Value>::deserialize(deserializer)?.map(|id| {
+            match id {
+                Value::String(id) => id,
+                id => id.to_string(),
+            }
+        })
+    )
+}
+
+// the API uses "2021-10-07T02:46:54.888210" for most, and RFC 3339 for some (Discord) dates
+fn parse_date(published: Option<&str>, added: Option<&str>) -> Option<NaiveDateTime> {
+    let parse = |date: &str| {
+        NaiveDateTime::parse_from_str(date, "%Y-%m-%dT%H:%M:%S%.f")
+            .ok()
+            .or_else(|| DateTime::parse_from_rfc3339(date).ok().map(|dt| dt.naive_utc()))
+    };
+
+    published.and_then(parse).or_else(|| added.and_then(parse))
 }
 
 #[derive(Debug, Error)]
 pub enum PostError {
     #[error("connection error")] Connect(String),
-    #[error("non-success status code")] Status(StatusCode),
+    #[error("non-success status code")] Status(StatusCode, Option<Duration>),
     #[error("malformed page data")] MalformedPage,
     #[error("malformed post data")] MalformedPost(String),
 }
 
 impl PostError {
     pub async fn try_interpret(&self, retries: usize) -> Result<()> {
-        async fn try_wait(retries: usize, duration: Duration, error: &str) -> Result<()> {
+        async fn try_wait(
+            retries: usize,
+            base: Duration,
+            retry_after: Option<Duration>,
+            error: &str
+        ) -> Result<()> {
             if retries < ARGUMENTS.max_tries - 1 {
-                sleep(duration).await;
+                retry::wait(base, retries + 1, retry_after).await;
                 Ok(())
             } else {
                 Err(format_err!("{error}"))
@@ -61,14 +295,21 @@ impl PostError {
 
         match self {
             PostError::Connect(err) | PostError::MalformedPost(err) => {
-                try_wait(retries, ARGUMENTS.retry_delay, err).await?;
+                try_wait(retries, ARGUMENTS.retry_delay, None, err).await?;
             }
-            PostError::Status(status) =>
+            PostError::Status(status, retry_after) =>
                 match status.as_u16() {
                     403 | 429 | 502..=504 => {
-                        try_wait(retries, ARGUMENTS.rate_limit_backoff, status.as_str()).await?;
+                        try_wait(
+                            retries,
+                            ARGUMENTS.rate_limit_backoff,
+                            *retry_after,
+                            status.as_str()
+                        ).await?;
+                    }
+                    _ => {
+                        try_wait(retries, ARGUMENTS.retry_delay, *retry_after, status.as_str()).await?;
                     }
-                    _ => try_wait(retries, ARGUMENTS.retry_delay, status.as_str()).await?,
                 }
             PostError::MalformedPage => unreachable!(),
         }
@@ -77,6 +318,22 @@ impl PostError {
     }
 }
 
+#[derive(Deserialize)]
+struct Embed {
+    url: Option<String>, // "https://mega.nz/folder/..."
+    // subject: Option<String>, // "Mega"
+    description: Option<String>,
+}
+
+impl Embed {
+    fn texts(embed: Option<&Self>) -> impl Iterator<Item = &str> {
+        embed
+            .into_iter()
+            .flat_map(|embed| [embed.url.as_deref(), embed.description.as_deref()])
+            .flatten()
+    }
+}
+
 #[derive(Deserialize)]
 pub struct SinglePost {
     post: SinglePostInner,
@@ -84,63 +341,91 @@ pub struct SinglePost {
 
 #[derive(Deserialize, Default)]
 struct SinglePostInner {
+    id: Option<String>,
+    title: Option<String>,
+    published: Option<String>,
+    added: Option<String>,
+    content: Option<String>,
+    embed: Option<Embed>,
     file: Option<PostFileRaw>,
     attachments: Vec<PostFileRaw>,
 }
 
 impl Post for SinglePost {
     fn files(&mut self) -> Vec<Arc<PostFile>> {
-        self.post.attachments.retain(|file| file.path.is_some());
-
-        if self.post.attachments.is_empty() && self.post.file.is_none() {
-            return Vec::new();
-        }
+        let post = self.meta();
+
+        collect_files(
+            &post,
+            self.post.file.take(),
+            mem::take(&mut self.post.attachments),
+            self.post.content.as_deref()
+        )
+    }
 
-        let attachments = mem::take(&mut self.post.attachments);
+    fn published(&self) -> Option<NaiveDateTime> {
+        parse_date(self.post.published.as_deref(), self.post.added.as_deref())
+    }
 
-        let mut post_files = Vec::with_capacity(attachments.len() + 1);
+    fn id(&self) -> Option<&str> {
+        self.post.id.as_deref()
+    }
 
-        for raw in attachments {
-            post_files.push(PostFile::new(raw.path.unwrap()));
-        }
+    fn content(&self) -> Option<&str> {
+        self.post.content.as_deref()
+    }
 
-        if let Some(raw) = self.post.file.take() && let Some(path) = raw.path {
-            post_files.push(PostFile::new(path));
-        }
+    fn links(&self) -> Vec<String> {
+        extract_links(self.content().into_iter().chain(Embed::texts(self.post.embed.as_ref())))
+    }
 
-        post_files
+    fn title(&self) -> Option<&str> {
+        self.post.title.as_deref()
     }
 }
 
 #[derive(Deserialize)]
 pub struct PagePost {
+    id: Option<String>,
+    title: Option<String>,
+    published: Option<String>,
+    added: Option<String>,
+    content: Option<String>,
+    embed: Option<Embed>,
     file: Option<PostFileRaw>,
     attachments: Vec<PostFileRaw>,
 }
 
 impl Post for PagePost {
     fn files(&mut self) -> Vec<Arc<PostFile>> {
-        self.attachments.retain(|file| file.path.is_some());
-
-        if self.attachments.is_empty() && self.file.is_none() {
-            return Vec::new();
-        }
+        let post = self.meta();
+
+        collect_files(
+            &post,
+            self.file.take(),
+            mem::take(&mut self.attachments),
+            self.content.as_deref()
+        )
+    }
 
-        let attachments = mem::take(&mut self.attachments);
+    fn published(&self) -> Option<NaiveDateTime> {
+        parse_date(self.published.as_deref(), self.added.as_deref())
+    }
 
-        let mut post_files = Vec::with_capacity(attachments.len() + 1);
+    fn id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
 
-        for raw in attachments {
-            if let Some(path) = raw.path {
-                post_files.push(PostFile::new(path));
-            }
-        }
+    fn content(&self) -> Option<&str> {
+        self.content.as_deref()
+    }
 
-        if let Some(raw) = self.file.take() && let Some(path) = raw.path {
-            post_files.push(PostFile::new(path));
-        }
+    fn links(&self) -> Vec<String> {
+        extract_links(self.content().into_iter().chain(Embed::texts(self.embed.as_ref())))
+    }
 
-        post_files
+    fn title(&self) -> Option<&str> {
+        self.title.as_deref()
     }
 }
 
@@ -152,25 +437,166 @@ pub struct DiscordChannel {
 
 #[derive(Deserialize)]
 pub struct DiscordPost {
+    id: Option<String>,
+    published: Option<String>,
     attachments: Vec<PostFileRaw>,
 }
 
 impl Post for DiscordPost {
     fn files(&mut self) -> Vec<Arc<PostFile>> {
-        self.attachments.retain(|file| file.path.is_some());
+        let post = self.meta();
 
-        if self.attachments.is_empty() {
-            return Vec::new();
-        }
+        collect_files(&post, None, mem::take(&mut self.attachments), None)
+    }
 
-        let attachments = mem::take(&mut self.attachments);
+    fn published(&self) -> Option<NaiveDateTime> {
+        parse_date(self.published.as_deref(), None)
+    }
 
-        let mut post_files = Vec::with_capacity(attachments.len());
+    fn id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+}
 
-        for raw in attachments {
-            post_files.push(PostFile::new(raw.path.unwrap()));
-        }
+#[derive(Deserialize)]
+pub struct DmPost {
+    #[serde(rename = "hash")]
+    id: Option<String>,
+    published: Option<String>,
+    added: Option<String>,
+    content: Option<String>,
+    file: Option<PostFileRaw>,
+    #[serde(default)]
+    attachments: Vec<PostFileRaw>,
+}
+
+impl Post for DmPost {
+    fn files(&mut self) -> Vec<Arc<PostFile>> {
+        let post = self.meta();
+
+        collect_files(
+            &post,
+            self.file.take(),
+            mem::take(&mut self.attachments),
+            self.content.as_deref()
+        )
+    }
+
+    fn published(&self) -> Option<NaiveDateTime> {
+        parse_date(self.published.as_deref(), self.added.as_deref())
+    }
+
+    fn id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+
+    fn content(&self) -> Option<&str> {
+        self.content.as_deref()
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CommunityPost {
+    id: Option<String>,
+    title: Option<String>,
+    published: Option<String>,
+    added: Option<String>,
+    content: Option<String>,
+    file: Option<PostFileRaw>,
+    #[serde(default)]
+    attachments: Vec<PostFileRaw>,
+}
+
+impl Post for CommunityPost {
+    fn files(&mut self) -> Vec<Arc<PostFile>> {
+        let post = self.meta();
+
+        collect_files(
+            &post,
+            self.file.take(),
+            mem::take(&mut self.attachments),
+            self.content.as_deref()
+        )
+    }
+
+    fn published(&self) -> Option<NaiveDateTime> {
+        parse_date(self.published.as_deref(), self.added.as_deref())
+    }
+
+    fn id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+
+    fn content(&self) -> Option<&str> {
+        self.content.as_deref()
+    }
+
+    fn title(&self) -> Option<&str> {
+        self.title.as_deref()
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Fancard {
+    #[serde(default, deserialize_with = "deserialize_id")]
+    id: Option<String>, // 104718
+    // user_id: String, // "1024383"
+    // hash: String, // "8d7d1b7a..."
+    // ext: String, // ".jpg"
+    // size: usize, // 1179582
+    added: Option<String>, // "2023-04-12T17:23:46.161498"
+    path: Option<String>, // "/8d/7d/8d7d1b7a...jpg"
+}
+
+impl Post for Fancard {
+    fn files(&mut self) -> Vec<Arc<PostFile>> {
+        let post = self.meta();
+
+        let raw = PostFileRaw {
+            name: None,
+            path: self.path.take(),
+        };
+
+        collect_files(&post, Some(raw), Vec::new(), None)
+    }
+
+    fn published(&self) -> Option<NaiveDateTime> {
+        parse_date(None, self.added.as_deref())
+    }
+
+    fn id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Announcement {
+    // service: String, // "patreon"
+    // user_id: String, // "5564244"
+    #[serde(rename = "hash")]
+    id: Option<String>, // "b0c4f1e2..."
+    content: Option<String>, // "<p>...</p>"
+    published: Option<String>, // "2023-04-12T17:23:46"
+    added: Option<String>, // "2023-04-12T17:23:46.161498"
+}
+
+impl Post for Announcement {
+    // announcements have no attachments, only files embedded in their content
+    fn files(&mut self) -> Vec<Arc<PostFile>> {
+        let post = self.meta();
+
+        collect_files(&post, None, Vec::new(), self.content.as_deref())
+    }
+
+    fn published(&self) -> Option<NaiveDateTime> {
+        parse_date(self.published.as_deref(), self.added.as_deref())
+    }
+
+    fn id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
 
-        post_files
+    fn content(&self) -> Option<&str> {
+        self.content.as_deref()
     }
 }
@@ -1,21 +1,45 @@
 use crate::{
-    cli::ARGUMENTS,
-    file::PostFile,
-    http::CLIENT,
-    post::{ self, DiscordChannel, DiscordPost, PagePost, Post, PostError, SinglePost },
+    cli::{ ARGUMENTS, Layout },
+    content,
+    file::{ PostFile, sanitize },
+    post::{
+        self,
+        Announcement,
+        CommunityPost,
+        DiscordChannel,
+        DiscordPost,
+        DmPost,
+        Fancard,
+        PagePost,
+        Post,
+        PostError,
+        PostMeta,
+        SinglePost,
+        WithRaw,
+    },
     pretty::{ self, n_fmt },
-    target::{ SubType, Target },
+    target::{ Site, SubType, Target },
 };
-use anyhow::{ Result, anyhow };
+use anyhow::{ Context, Result, anyhow };
 use indicatif::{ ProgressBar, ProgressStyle };
-use serde::Deserialize;
-use std::{ collections::HashSet, fmt::{ self, Display, Formatter, Write }, sync::Arc, thread };
+use chrono::NaiveDateTime;
+use serde::{ Deserialize, Serialize, de::DeserializeOwned };
+use std::{
+    collections::HashSet,
+    fmt::{ self, Display, Formatter, Write },
+    fs::{ self, File },
+    io::{ ErrorKind, Write as ioWrite },
+    mem,
+    path::{ Path, PathBuf },
+    sync::Arc,
+    thread,
+};
 use tokio::{ sync::mpsc::{ UnboundedReceiver, unbounded_channel }, time::{ Duration, sleep } };
 
 #[derive(Deserialize)]
 struct Creator {
     // id: usize, // "1024383"
-    // name: String, // "sspr"
+    name: String, // "sspr"
     // service: Service, // "fanbox"
     // indexed: String, // "2021-10-07T02:46:54.888210"
     // updated: String, // "2025-05-30T13:38:51.561232"
@@ -27,18 +51,35 @@ struct Creator {
     // chat_count: usize, // 0
 }
 
+// returns the target's files, the IDs of posts which have not been seen before (--update)
+// and whether all pages could be retrieved
 pub async fn try_get_files(
     target: Arc<Target>,
     target_id: usize
-) -> Result<HashSet<Arc<PostFile>>> {
-    Ok(Profile::try_new(target, target_id).await?.files)
+) -> Result<(HashSet<Arc<PostFile>>, Vec<String>, bool)> {
+    let profile = Profile::try_new(target, target_id).await?;
+    Ok((profile.files, profile.new_post_ids, profile.complete))
+}
+
+// a line of links.jsonl
+#[derive(Serialize)]
+struct PostLink<'a> {
+    post_id: Option<&'a str>,
+    post_title: Option<&'a str>,
+    published: Option<NaiveDateTime>,
+    url: &'a str,
 }
 
 struct Profile {
     target_id: usize,
     target: Arc<Target>,
     posts: Vec<Box<dyn Post>>,
+    post_count: usize,
+    seen_post_ids: HashSet<String>,
+    new_post_ids: Vec<String>,
     files: HashSet<Arc<PostFile>>,
+    // false if malformed pages were skipped
+    complete: bool,
 }
 
 impl Display for Profile {
@@ -55,17 +96,15 @@ impl Display for Profile {
                 );
             }
             _ => {
-                let cap = self.posts.capacity();
-
                 let _ = write!(
                     f,
                     "#{number}: {target} has {posts}",
                     number = n_fmt(self.target_id),
                     target = self.target,
-                    posts = pretty::with_word(cap, "post")
+                    posts = pretty::with_word(self.post_count, "post")
                 );
 
-                if cap > 0 {
+                if self.post_count > 0 {
                     let _ = match self.files.len() {
                         0 => write!(f, ", but no files"),
                         1 => write!(f, ", containing 1 file"),
@@ -98,36 +137,31 @@ impl Profile {
         let mut post_count = 0;
 
         if let Target::Creator { service, user, .. } = &*target {
-            let host = service.host();
+            let base = service.base_url();
             let service = service.as_static_str();
             let mut url = String::with_capacity(
-                8 + host.len() + 8 + service.len() + 6 + user.len() + 8
+                base.len() + 8 + service.len() + 6 + user.len() + 8
             );
-            let _ = write!(url, "https://{host}/api/v1/{service}/user/{user}/profile");
-
-            let mut tries = 0;
+            let _ = write!(url, "{base}/api/v1/{service}/user/{user}/profile");
 
-            loop {
-                match CLIENT.get(&url).send().await {
-                    Ok(res) => {
-                        post_count = res.json::<Creator>().await?.post_count;
-                        break;
-                    }
-                    Err(err) => {
-                        tries += 1;
-                        if tries > ARGUMENTS.max_tries {
-                            return Err(anyhow!(err));
-                        }
-                    }
-                }
-            }
+            let creator = post::try_fetch_retrying::<Creator>(&url).await?;
+            post_count = creator.post_count;
+            target.set_creator_name(creator.name);
         }
 
         let mut profile = Self {
             target_id,
             target: target.clone(),
             posts: Vec::with_capacity(post_count),
+            post_count: 0,
+            seen_post_ids: if ARGUMENTS.update.is_some() {
+                target.try_read_seen()?
+            } else {
+                HashSet::new()
+            },
+            new_post_ids: Vec::new(),
             files: HashSet::new(),
+            complete: true,
         };
 
         match &*target {
@@ -142,7 +176,11 @@ impl Profile {
         // wait for page progress bar to finish
         sleep(Duration::from_millis(1)).await;
 
-        profile.init_files();
+        if ARGUMENTS.since.is_some() || ARGUMENTS.until.is_some() {
+            profile.posts.retain(|post| ARGUMENTS.in_date_range(post.published()));
+        }
+
+        profile.init_files()?;
 
         eprintln!("{profile}");
 
@@ -154,109 +192,179 @@ impl Profile {
     }
 
     async fn init_posts_standard(&mut self, user: &str, subtype: &SubType) -> Result<()> {
-        if let SubType::Post(post) = subtype {
-            let host = self.target.as_service().host();
-            let service = self.target.as_service().as_static_str();
+        match subtype {
+            SubType::Post(post) => self.init_post_single(user, post).await,
+            SubType::PageOffset(offset) => {
+                self.init_posts_paginated::<PagePost>(user, "posts", Some(*offset)).await
+            }
+            SubType::None => self.init_posts_paginated::<PagePost>(user, "posts", None).await,
+            SubType::Dms => self.init_posts_paginated::<DmPost>(user, "dms", None).await,
+            SubType::Community => {
+                self.init_posts_paginated::<CommunityPost>(user, "community", None).await
+            }
+            SubType::Fancards => self.init_posts_unpaginated::<Fancard>(user, "fancards").await,
+            SubType::Announcements => {
+                self.init_posts_unpaginated::<Announcement>(user, "announcements").await
+            }
+        }
+    }
 
-            let mut url = String::with_capacity(
-                8 + host.len() + 8 + service.len() + 6 + user.len() + 6 + post.len()
-            );
-            let _ = write!(url, "https://{host}/api/v1/{service}/user/{user}/post/{post}");
+    async fn init_post_single(&mut self, user: &str, post: &str) -> Result<()> {
+        let base = self.target.as_service().base_url();
+        let service = self.target.as_service().as_static_str();
 
-            let mut tries = 0;
+        let mut url = String::with_capacity(
+            base.len() + 8 + service.len() + 6 + user.len() + 6 + post.len()
+        );
+        let _ = write!(url, "{base}/api/v1/{service}/user/{user}/post/{post}");
 
-            loop {
-                match CLIENT.get(&url).send().await {
-                    Ok(post) => {
-                        self.posts.push(Box::new(post.json::<SinglePost>().await?));
-                        break;
-                    }
-                    Err(err) => {
-                        tries += 1;
-                        if tries > ARGUMENTS.max_tries {
-                            return Err(anyhow!(err));
-                        }
-                    }
+        self.posts.push(Box::new(post::try_fetch_retrying::<WithRaw<SinglePost>>(&url).await?));
+
+        Ok(())
+    }
+
+    // endpoints which return all entries at once (fancards, announcements)
+    async fn init_posts_unpaginated<P: Post + DeserializeOwned + 'static>(
+        &mut self,
+        user: &str,
+        endpoint: &str
+    ) -> Result<()> {
+        let base = self.target.as_service().base_url();
+        let service = self.target.as_service().as_static_str();
+
+        let mut url = String::with_capacity(
+            base.len() + 8 + service.len() + 6 + user.len() + 1 + endpoint.len()
+        );
+        let _ = write!(url, "{base}/api/v1/{service}/user/{user}/{endpoint}");
+
+        let mut retries = 0;
+
+        let posts: Vec<WithRaw<P>> = loop {
+            match post::try_fetch(&url).await {
+                Ok(p) => {
+                    break p;
+                }
+                Err(PostError::MalformedPage) => {
+                    self.complete = false;
+                    return Ok(());
+                }
+                Err(err) => {
+                    err.try_interpret(retries).await?;
+                    retries += 1;
                 }
             }
-        } else {
-            let (msg_tx, msg_rx) = unbounded_channel::<String>();
+        };
+
+        for post in posts {
+            self.posts.push(Box::new(post));
+        }
+
+        Ok(())
+    }
 
-            thread::spawn(move || page_progress(msg_rx));
+    // endpoints which return 50 entries per page (posts, dms, community)
+    async fn init_posts_paginated<P: Post + DeserializeOwned + 'static>(
+        &mut self,
+        user: &str,
+        endpoint: &str,
+        page_offset: Option<usize>
+    ) -> Result<()> {
+        let (msg_tx, msg_rx) = unbounded_channel::<String>();
 
-            let mut offset = if let SubType::PageOffset(o) = subtype { *o } else { 0 };
+        thread::spawn(move || page_progress(msg_rx));
 
-            let mut page = String::with_capacity(3);
+        let mut offset = page_offset.unwrap_or_default();
 
-            let host = self.target.as_service().host();
-            let service = self.target.as_service().as_static_str();
+        let mut page = String::with_capacity(3);
 
-            let msg_len = 21 + service.len() + 1 + user.len() + 7;
-            let mut msg = String::with_capacity(msg_len);
-            let _ = write!(msg, "Retrieving posts for {service}/{user} page #");
+        let mut seen_pages = 0;
 
-            let url_len = 8 + host.len() + 8 + service.len() + 6 + user.len() + 9;
-            let mut url = String::with_capacity(url_len + 5);
-            let _ = write!(url, "https://{host}/api/v1/{service}/user/{user}/posts?o=");
+        let base = self.target.as_service().base_url();
+        let service = self.target.as_service().as_static_str();
 
-            loop {
-                let mut retries = 0;
+        let msg_len = 11 + endpoint.len() + 5 + service.len() + 1 + user.len() + 7;
+        let mut msg = String::with_capacity(msg_len);
+        let _ = write!(msg, "Retrieving {endpoint} for {service}/{user} page #");
 
-                let mut page_posts = Vec::<PagePost>::new();
+        let url_len = base.len() + 8 + service.len() + 6 + user.len() + 1 + endpoint.len() + 3;
+        let mut url = String::with_capacity(url_len + 5);
+        let _ = write!(url, "{base}/api/v1/{service}/user/{user}/{endpoint}?o=");
 
-                page.clear();
-                let _ = write!(page, "{}", (offset + 50) / 50);
+        loop {
+            let mut retries = 0;
 
-                msg.truncate(msg_len);
-                let _ = write!(msg, "{page}");
+            let mut page_posts = Vec::<WithRaw<P>>::new();
 
-                url.truncate(url_len);
-                let _ = write!(url, "{offset}");
+            page.clear();
+            let _ = write!(page, "{}", (offset + 50) / 50);
 
-                let mut skip = false;
+            msg.truncate(msg_len);
+            let _ = write!(msg, "{page}");
 
-                loop {
-                    if retries > 0 {
-                        msg.truncate(msg_len + page.len());
-                        let _ = write!(msg, " (Retry #{retries})");
-                    }
+            url.truncate(url_len);
+            let _ = write!(url, "{offset}");
 
-                    msg_tx.send(msg.clone())?;
+            let mut skip = false;
 
-                    match post::try_fetch(&url).await {
-                        Ok(p) => {
-                            page_posts = p;
+            loop {
+                if retries > 0 {
+                    msg.truncate(msg_len + page.len());
+                    let _ = write!(msg, " (Retry #{retries})");
+                }
+
+                msg_tx.send(msg.clone())?;
+
+                match post::try_fetch(&url).await {
+                    Ok(p) => {
+                        page_posts = p;
+                        break;
+                    }
+                    Err(err) => {
+                        if let PostError::MalformedPage = err {
+                            skip = true;
                             break;
                         }
-                        Err(err) => {
-                            if let PostError::MalformedPage = err {
-                                skip = true;
-                                break;
-                            }
-                            err.try_interpret(retries).await?;
-                            retries += 1;
-                        }
+                        err.try_interpret(retries).await?;
+                        retries += 1;
                     }
                 }
+            }
 
-                offset += 50;
+            offset += 50;
 
-                if skip {
-                    continue;
-                }
+            if skip {
+                self.complete = false;
+                continue;
+            }
 
-                if page_posts.is_empty() {
-                    break;
-                }
+            if page_posts.is_empty() {
+                break;
+            }
 
-                for post in page_posts {
-                    self.posts.push(Box::new(post));
-                }
+            // posts are sorted newest first, so later pages will be even older
+            if page_posts.iter().all(|post| ARGUMENTS.before_since(post.published())) {
+                break;
+            }
 
-                if let SubType::PageOffset(_) = subtype {
-                    break;
+            if let Some(max_seen_pages) = ARGUMENTS.update {
+                if page_posts.iter().all(|post| self.is_seen(post)) {
+                    seen_pages += 1;
+
+                    if seen_pages >= max_seen_pages {
+                        break;
+                    }
+                } else {
+                    seen_pages = 0;
                 }
             }
+
+            for post in page_posts {
+                self.posts.push(Box::new(post));
+            }
+
+            if page_offset.is_some() {
+                break;
+            }
         }
 
         Ok(())
@@ -274,10 +382,10 @@ impl Profile {
                 id: channel.clone(),
             }]
         } else {
-            let mut url = String::with_capacity(48 + server.len());
-            let _ = write!(url, "https://kemono.cr/api/v1/discord/channel/lookup/{server}");
-            // TODO: add retry loop
-            post::try_fetch(&url).await?
+            let base = Site::Kemono.base_url();
+            let mut url = String::with_capacity(base.len() + 40 + server.len());
+            let _ = write!(url, "{base}/api/v1/discord/channel/lookup/{server}");
+            post::try_fetch_retrying(&url).await?
         };
 
         if channels.is_empty() {
@@ -308,18 +416,19 @@ impl Profile {
                 channel = channel.id
             );
 
-            let url_len = 41 + channel.id.len() + 3;
+            let base = Site::Kemono.base_url();
+            let url_len = base.len() + 24 + channel.id.len() + 3;
             let mut url = String::with_capacity(url_len + 5);
             let _ = write!(
                 url,
-                "https://kemono.cr/api/v1/discord/channel/{channel}?o=",
+                "{base}/api/v1/discord/channel/{channel}?o=",
                 channel = channel.id
             );
 
             loop {
                 let mut retries = 0;
 
-                let mut page_posts = Vec::<DiscordPost>::new();
+                let mut page_posts = Vec::<WithRaw<DiscordPost>>::new();
 
                 page.clear();
                 let _ = write!(page, "{}", (offset + 150) / 150);
@@ -359,6 +468,7 @@ impl Profile {
                 offset += 150;
 
                 if skip {
+                    self.complete = false;
                     break;
                 }
 
@@ -366,6 +476,10 @@ impl Profile {
                     break;
                 }
 
+                if page_posts.iter().all(|post| ARGUMENTS.before_since(post.published())) {
+                    break;
+                }
+
                 for post in page_posts {
                     self.posts.push(Box::new(post));
                 }
@@ -379,13 +493,192 @@ impl Profile {
         Ok(())
     }
 
-    fn init_files(&mut self) {
-        self.posts.drain(..).for_each(|mut post| {
-            post.files()
-                .into_iter()
-                .for_each(|file| {
-                    self.files.insert(file);
-                });
+    // directory of the post's first file, or where its files would be stored.
+    // path templates are rendered per file, so posts without files have no directory.
+    fn post_dir(&self, post: &PostMeta, files: &[Arc<PostFile>]) -> Option<PathBuf> {
+        if let Some(file) = files.first() {
+            return file.to_pathbuf(&self.target).parent().map(Path::to_path_buf);
+        }
+
+        if ARGUMENTS.path_template.is_some() {
+            return None;
+        }
+
+        let mut path = self.target.as_pathbuf().clone();
+
+        if ARGUMENTS.layout == Layout::Post && let Some(dir) = post.to_dir_name() {
+            path.push(dir);
+        }
+
+        Some(path)
+    }
+
+    // metadata/content files of a post, stored next to its files
+    fn try_write_sidecars(&self, post: &dyn Post, files: &[Arc<PostFile>]) -> Result<()> {
+        let Some(id) = post.id() else {
+            return Ok(());
+        };
+
+        let Some(dir) = self.post_dir(&post.meta(), files) else {
+            return Ok(());
+        };
+
+        if ARGUMENTS.write_json && let Some(raw) = post.raw() {
+            Self::try_write_sidecar(&dir, id, "json", &serde_json::to_vec_pretty(raw)?)?;
+        }
+
+        if
+            let Some(format) = ARGUMENTS.write_content &&
+            let Some(text) = post.content() &&
+            !text.trim().is_empty()
+        {
+            // files of other posts are only stored once, in the directory of the first post
+            let files: Vec<Arc<PostFile>> = files
+                .iter()
+                .map(|file| self.files.get(file).unwrap_or(file).clone())
+                .collect();
+
+            let text = content::render(format, post.title(), text, &files, &dir, &self.target)?;
+
+            Self::try_write_sidecar(&dir, id, format.as_ext(), text.as_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    fn try_write_sidecar(dir: &Path, id: &str, ext: &str, contents: &[u8]) -> Result<()> {
+        fs::create_dir_all(dir)?;
+
+        // DM and announcement IDs are hashes, so "{id}.{ext}" could be mistaken for a file
+        let path = dir.join({
+            let mut name = String::with_capacity(id.len() + 6 + ext.len());
+            let _ = write!(name, "{id}.post.{ext}");
+            sanitize(&name)
         });
+
+        // leave unchanged files (and their modification time) alone
+        if fs::read(&path).is_ok_and(|existing| existing == contents) {
+            return Ok(());
+        }
+
+        fs::write(&path, contents).with_context(|| {
+            let mut buf = String::with_capacity(15 + path.as_os_str().len());
+            let _ = write!(buf, "write post {ext}: {}", path.display());
+            buf
+        })
+    }
+
+    // lines already present (from previous runs) are skipped
+    fn try_append_lines(path: &Path, lines: &[String]) -> Result<()> {
+        if lines.is_empty() {
+            return Ok(());
+        }
+
+        let existing = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == ErrorKind::NotFound => String::new(),
+            Err(err) => {
+                return Err(err.into());
+            }
+        };
+
+        let mut seen: HashSet<&str> = existing.lines().collect();
+
+        let mut new_lines = String::new();
+
+        for line in lines {
+            if seen.insert(line) {
+                new_lines.push_str(line);
+                new_lines.push('\n');
+            }
+        }
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        File::options()
+            .append(true)
+            .create(true)
+            .open(path)
+            .and_then(|mut file| file.write_all(new_lines.as_bytes()))
+            .with_context(|| {
+                let mut buf = String::with_capacity(14 + path.as_os_str().len());
+                let _ = write!(buf, "write links: {}", path.display());
+                buf
+            })
+    }
+
+    fn is_seen(&self, post: &dyn Post) -> bool {
+        post.id().is_some_and(|id| self.seen_post_ids.contains(id))
+    }
+
+    fn init_files(&mut self) -> Result<()> {
+        self.post_count = self.posts.len();
+
+        if ARGUMENTS.update.is_some() {
+            self.new_post_ids = self.posts
+                .iter()
+                .filter(|post| !self.is_seen(post.as_ref()))
+                .filter_map(|post| post.id().map(ToString::to_string))
+                .collect();
+        }
+
+        let mut links = (Vec::new(), Vec::new());
+
+        for mut post in mem::take(&mut self.posts) {
+            if ARGUMENTS.extract_links {
+                for url in post.links() {
+                    let line = serde_json::to_string(
+                        &(PostLink {
+                            post_id: post.id(),
+                            post_title: post.title(),
+                            published: post.published(),
+                            url: &url,
+                        })
+                    )?;
+
+                    links.1.push(line);
+                    links.0.push(url);
+                }
+            }
+
+            let files = post.files();
+
+            self.files.extend(files.iter().cloned());
+
+            if
+                (ARGUMENTS.write_json || ARGUMENTS.write_content.is_some()) &&
+                !ARGUMENTS.is_dry_run()
+            {
+                self.try_write_sidecars(post.as_ref(), &files)?;
+            }
+        }
+
+        if ARGUMENTS.extract_links && !ARGUMENTS.is_dry_run() {
+            let dir = self.target.as_pathbuf();
+            Self::try_append_lines(&dir.join("links.txt"), &links.0)?;
+            Self::try_append_lines(&dir.join("links.jsonl"), &links.1)?;
+        }
+
+        // files sharing a local path would be written to concurrently
+        if ARGUMENTS.path_template.is_some() || ARGUMENTS.filename_template.is_some() {
+            let mut paths = HashSet::with_capacity(self.files.len());
+
+            for file in &self.files {
+                let path = file.to_pathbuf(&self.target);
+
+                if !paths.insert(path.clone()) {
+                    return Err(
+                        anyhow!(
+                            "template produces the same path for multiple files: {}\nconsider adding {{hash}} (or {{post_id}} and {{index}}) to the template",
+                            path.display()
+                        )
+                    );
+                }
+            }
+        }
+
+        Ok(())
     }
 }
@@ -1,13 +1,19 @@
-use crate::{ cli::ARGUMENTS, file::PostFile, pretty::{ n_fmt, with_word } };
+use crate::{
+    cli::ARGUMENTS,
+    concurrency::Concurrency,
+    file::PostFile,
+    pretty::{ n_fmt, with_word },
+    shutdown::{ self, Interrupted },
+};
+use anyhow::Context;
 use indicatif::{ HumanBytes, ProgressBar, ProgressStyle };
 use itertools::Itertools;
 use std::{
     collections::HashMap,
     fmt::{ Display, Formatter, Result, Write },
     fs::File,
-    io::{ IoSlice, Write as ioWrite },
-    path::PathBuf,
-    process::exit,
+    io::{ self, IoSlice, Write as ioWrite },
+    path::Path,
     sync::{ Arc, atomic::{ AtomicBool, Ordering::Relaxed } },
     time::{ Duration, Instant },
 };
@@ -22,10 +28,30 @@ pub enum DownloadAction {
     Skip(Arc<PostFile>),
     Fail(String, Arc<PostFile>),
     Complete(Arc<PostFile>),
-    Panic(String),
+    // linked to an existing local copy (--dedup)
+    Link(Arc<PostFile>),
+    // stopped by a shutdown, to be resumed by the next run
+    Interrupt(Arc<PostFile>),
     Update,
 }
 
+impl DownloadAction {
+    pub fn from_error(err: &anyhow::Error, file: Arc<PostFile>) -> Self {
+        if err.is::<Interrupted>() {
+            return DownloadAction::Interrupt(file);
+        }
+
+        let mut error = err.to_string();
+
+        if let Some(source) = err.source() {
+            error.push('\n');
+            error.push_str(&source.to_string());
+        }
+
+        DownloadAction::Fail(error, file)
+    }
+}
+
 struct Stats {
     start_time: Instant,
 
@@ -33,24 +59,28 @@ struct Stats {
     waiting: usize,
     active: usize,
     complete: usize,
+    linked: usize,
     skipped: usize,
     failed: usize,
+    interrupted: usize,
 
     dl_bytes: u64,
 
-    panic: bool,
-
     error: String,
 
     archive_file: Option<File>,
+    archive_error: Option<io::Error>,
 
     files_by_type: HashMap<String, usize>,
+
+    // effective number of simultaneous downloads (--adaptive)
+    limit: Option<usize>,
 }
 
 impl Stats {
     pub fn new(
         files: usize,
-        archive_path: &PathBuf,
+        archive_file: Option<File>,
         files_by_type: HashMap<String, usize>
     ) -> Self {
         Self {
@@ -60,35 +90,22 @@ impl Stats {
             waiting: 0,
             active: 0,
             complete: 0,
+            linked: 0,
             skipped: 0,
             failed: 0,
+            interrupted: 0,
 
             dl_bytes: 0,
 
-            panic: false,
-
             error: String::new(),
 
-            archive_file: if ARGUMENTS.download_archive {
-                Some(Self::open_archive(archive_path))
-            } else {
-                None
-            },
+            archive_file,
+            archive_error: None,
 
             files_by_type,
-        }
-    }
 
-    fn open_archive(path: &PathBuf) -> File {
-        File::options()
-            .append(true)
-            .create(true)
-            .truncate(false)
-            .open(path)
-            .unwrap_or_else(|err| {
-                eprintln!("failed to open archive file {path}: {err}", path = path.display());
-                exit(5);
-            })
+            limit: None,
+        }
     }
 
     fn write_to_archive(&mut self, hash: Option<&str>) {
@@ -99,13 +116,18 @@ impl Stats {
         {
             let slices = [IoSlice::new(hash.as_bytes()), IoSlice::new(b"\n")];
             if let Err(err) = archive.write_vectored(&slices) {
-                eprintln!("{err}");
-                exit(6);
+                // later downloads couldn't be logged either
+                let mut error = String::with_capacity(30);
+                let _ = write!(error, "failed to write archive file: {err}");
+                self.error = error;
+                self.archive_file = None;
+                self.archive_error = Some(err);
+                shutdown::request();
             }
         }
     }
 
-    fn update(&mut self, download_state: DownloadAction) -> bool {
+    fn update(&mut self, download_state: DownloadAction, concurrency: &Concurrency) -> bool {
         match download_state {
             DownloadAction::Start => {
                 self.queued -= 1;
@@ -115,6 +137,7 @@ impl Stats {
             DownloadAction::Wait => {
                 self.active -= 1;
                 self.waiting += 1;
+                concurrency.decrease();
                 false
             }
             DownloadAction::Continue => {
@@ -148,15 +171,24 @@ impl Stats {
                 true
             }
             DownloadAction::Complete(post_file) => {
+                concurrency.increase();
                 self.active -= 1;
                 self.complete += 1;
                 self.detract_one_from_file_counter(post_file.get_ext());
                 self.write_to_archive(post_file.get_hash());
                 true
             }
-            DownloadAction::Panic(error) => {
-                self.error = error;
-                self.panic = true;
+            DownloadAction::Link(post_file) => {
+                self.active -= 1;
+                self.linked += 1;
+                self.detract_one_from_file_counter(post_file.get_ext());
+                self.write_to_archive(post_file.get_hash());
+                true
+            }
+            DownloadAction::Interrupt(post_file) => {
+                self.active -= 1;
+                self.interrupted += 1;
+                self.detract_one_from_file_counter(post_file.get_ext());
                 false
             }
             DownloadAction::Update => false,
@@ -190,15 +222,40 @@ impl Display for Stats {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
         writeln!(
             f,
-            "downloaded {} (avg. {}/s) / {} queued / {} waiting / {} active / {} complete / {} skipped / {} failed{}",
+            "downloaded {} (avg. {}/s) / {} queued / {} waiting / {} active{} / {} complete{} / {} skipped / {} failed{}{}",
             HumanBytes(self.dl_bytes),
             self.bytes_per_sec(),
             n_fmt(self.queued),
             n_fmt(self.waiting),
             n_fmt(self.active),
+            match self.limit {
+                Some(limit) => {
+                    let limit = n_fmt(limit);
+                    let mut buf = String::with_capacity(8 + limit.len());
+                    let _ = write!(buf, " (limit {limit})");
+                    buf
+                }
+                None => String::new(),
+            },
             n_fmt(self.complete),
+            if ARGUMENTS.dedup.is_some() {
+                let linked = n_fmt(self.linked);
+                let mut buf = String::with_capacity(10 + linked.len());
+                let _ = write!(buf, " / {linked} linked");
+                buf
+            } else {
+                String::new()
+            },
             n_fmt(self.skipped),
             n_fmt(self.failed),
+            if self.interrupted == 0 {
+                String::new()
+            } else {
+                let interrupted = n_fmt(self.interrupted);
+                let mut buf = String::with_capacity(15 + interrupted.len());
+                let _ = write!(buf, " / {interrupted} interrupted");
+                buf
+            },
             if self.files_by_type.is_empty() {
                 String::new()
             } else {
@@ -222,14 +279,28 @@ impl Display for Stats {
 
 pub static DOWNLOADS_FAILED: AtomicBool = AtomicBool::new(false);
 
-#[allow(clippy::needless_pass_by_value)]
+pub fn try_open_archive(path: &Path) -> anyhow::Result<File> {
+    File::options()
+        .append(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .with_context(|| {
+            let mut buf = String::with_capacity(26 + path.as_os_str().len());
+            let _ = write!(buf, "failed to open archive file {}", path.display());
+            buf
+        })
+}
+
+// Runs until all senders are dropped, i.e. all downloads of the target are done.
 pub fn progress_bar(
     files: usize,
-    archive: PathBuf,
+    archive_file: Option<File>,
     mut msg_rx: UnboundedReceiver<DownloadAction>,
     last_target: bool,
-    files_by_type: HashMap<String, usize>
-) {
+    files_by_type: HashMap<String, usize>,
+    concurrency: &Concurrency
+) -> anyhow::Result<()> {
     let bar = ProgressBar::new(files as u64);
 
     bar.set_style(
@@ -242,15 +313,19 @@ pub fn progress_bar(
 
     bar.enable_steady_tick(Duration::from_millis(200));
 
-    let mut stats = Stats::new(files, &archive, files_by_type);
+    let mut stats = Stats::new(files, archive_file, files_by_type);
 
     let mut errors = String::new();
 
     while let Some(state) = msg_rx.blocking_recv() {
-        if stats.update(state) {
+        if stats.update(state, concurrency) {
             bar.inc(1);
         }
 
+        if ARGUMENTS.adaptive {
+            stats.limit = Some(concurrency.limit());
+        }
+
         if !stats.error.is_empty() {
             errors.clear();
             let _ = write!(errors, "\n{}", stats.error);
@@ -258,14 +333,14 @@ pub fn progress_bar(
         }
 
         bar.set_prefix(stats.to_string());
-
-        if stats.panic {
-            bar.finish();
-            exit(7);
-        }
     }
 
-    bar.finish();
+    // keep the position of unfinished downloads
+    if shutdown::requested() {
+        bar.abandon();
+    } else {
+        bar.finish();
+    }
 
     if !last_target {
         eprint!("\n\n");
@@ -274,4 +349,14 @@ pub fn progress_bar(
     if stats.failed != 0 {
         DOWNLOADS_FAILED.store(true, Relaxed);
     }
+
+    if let Some(err) = stats.archive_error {
+        return Err(err).context("failed to write archive file");
+    }
+
+    if let Some(archive) = stats.archive_file {
+        archive.sync_data().context("failed to flush archive file")?;
+    }
+
+    Ok(())
 }
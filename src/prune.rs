@@ -0,0 +1,125 @@
+use crate::{
+    cli::{ ARGUMENTS, PruneAction },
+    file::PostFile,
+    pretty::with_word,
+    target::{ SubType, Target },
+    verify::{ self, Kind },
+};
+use anyhow::Result;
+use std::{ collections::HashSet, fmt::Write, path::PathBuf, sync::Arc };
+
+// Removes local files of a target that are not part of its remote file set
+// (--prune). Temporary, metadata, content and link files are left alone.
+// `complete` is false if pages of the target could not be retrieved, their files
+// would be missing from the remote file set.
+#[allow(clippy::cast_precision_loss)]
+pub fn try_prune(
+    target: &Target,
+    files: &HashSet<Arc<PostFile>>,
+    complete: bool,
+    action: PruneAction
+) -> Result<()> {
+    let root = target.as_pathbuf();
+
+    if !target.is_whole_dir() {
+        eprintln!("{target}: not pruning, the target only covers part of {}", root.display());
+        return Ok(());
+    }
+
+    if !complete {
+        eprintln!("{target}: not pruning, some pages could not be retrieved");
+        return Ok(());
+    }
+
+    if !root.is_dir() {
+        return Ok(());
+    }
+
+    // content outside of regular posts is stored in subdirectories of the creator's
+    // directory, but belongs to separate targets
+    let skip: Vec<PathBuf> = if target.as_subtype_dir().is_none() {
+        [SubType::Dms, SubType::Fancards, SubType::Announcements, SubType::Community]
+            .iter()
+            .filter_map(SubType::as_dir)
+            .map(|dir| root.join(dir))
+            .collect()
+    } else {
+        Vec::new()
+    };
+
+    let expected: HashSet<PathBuf> = files
+        .iter()
+        .map(|file| file.to_pathbuf(target))
+        .collect();
+
+    let mut local = 0;
+    let mut orphaned = Vec::new();
+
+    for path in verify::try_walk(root, &skip)? {
+        if matches!(verify::classify(&path), Kind::Temp | Kind::Known) {
+            continue;
+        }
+
+        local += 1;
+
+        if !expected.contains(&path) {
+            orphaned.push(path);
+        }
+    }
+
+    if orphaned.is_empty() {
+        return Ok(());
+    }
+
+    orphaned.sort();
+
+    for path in &orphaned {
+        eprintln!("orphaned: {}", path.display());
+    }
+
+    let share = (orphaned.len() as f64) * 100.0 / (local as f64);
+
+    if share > ARGUMENTS.prune_threshold {
+        let mut msg = String::with_capacity(160);
+
+        let _ = write!(
+            msg,
+            "{target}: {} of {local} local files are no longer present remotely ({share:.1}%), above --prune-threshold {}%",
+            orphaned.len(),
+            ARGUMENTS.prune_threshold
+        );
+
+        if ARGUMENTS.is_dry_run() {
+            eprintln!("{msg}, pruning would be skipped");
+        } else {
+            eprintln!("{msg}, nothing was removed");
+        }
+
+        return Ok(());
+    }
+
+    if ARGUMENTS.is_dry_run() {
+        eprintln!("{target}: {} would be pruned", with_word(orphaned.len(), "file"));
+        return Ok(());
+    }
+
+    for path in &orphaned {
+        match action {
+            PruneAction::Trash => {
+                verify::try_move_aside(path, "trash")?;
+            }
+            PruneAction::Delete => verify::try_delete(path)?,
+        }
+    }
+
+    eprintln!(
+        "{target}: pruned {} ({})",
+        with_word(orphaned.len(), "file"),
+        match action {
+            PruneAction::Trash => "moved to trash",
+            PruneAction::Delete => "deleted",
+        }
+    );
+
+    Ok(())
+}
@@ -0,0 +1,46 @@
+use crate::{ cli::ARGUMENTS, shutdown };
+use chrono::DateTime;
+use reqwest::header::{ HeaderMap, RETRY_AFTER };
+use std::time::SystemTime;
+use tokio::time::Duration;
+
+// Retry-After as delay in seconds ("120") or HTTP date ("Wed, 21 Oct 2015 07:28:00 GMT")
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+
+    let date = SystemTime::from(DateTime::parse_from_rfc2822(value).ok()?);
+
+    Some(date.duration_since(SystemTime::now()).unwrap_or_default())
+}
+
+// Delay before the given retry (starting at 1): the server's Retry-After if present,
+// otherwise the base delay doubled for every retry. Both are capped by --max-backoff and
+// jittered, so concurrent tasks that failed at the same time don't retry in lockstep.
+pub fn backoff(base: Duration, retry: usize, retry_after: Option<Duration>) -> Duration {
+    let max = ARGUMENTS.max_backoff;
+
+    match retry_after {
+        Some(delay) => {
+            let delay = delay.min(max);
+            delay + jitter(delay.min(base) / 2)
+        }
+        None => {
+            let exponent = u32::try_from(retry.saturating_sub(1)).unwrap_or(u32::MAX).min(16);
+            let delay = base.saturating_mul(1 << exponent).min(max);
+            delay / 2 + jitter(delay / 2)
+        }
+    }
+}
+
+pub async fn wait(base: Duration, retry: usize, retry_after: Option<Duration>) {
+    shutdown::sleep(backoff(base, retry, retry_after)).await;
+}
+
+// random duration in 0..=max
+fn jitter(max: Duration) -> Duration {
+    max.mul_f64(fastrand::f64())
+}
@@ -0,0 +1,75 @@
+use anyhow::Result;
+use std::{ pin::pin, process::exit, sync::atomic::{ AtomicBool, Ordering::Relaxed } };
+use thiserror::Error;
+use tokio::{ signal, sync::Notify, time::{ self, Duration } };
+
+#[cfg(unix)]
+use tokio::signal::unix::SignalKind;
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+static NOTIFY: Notify = Notify::const_new();
+
+// returned by downloads that were stopped between requests, their state is kept for resuming
+#[derive(Debug, Error)]
+#[error("interrupted")]
+pub struct Interrupted;
+
+// The first SIGINT/SIGTERM stops new downloads and lets running requests
+// finish, the second one quits immediately.
+pub fn try_listen() -> Result<()> {
+    #[cfg(unix)]
+    let mut terminate = signal::unix::signal(SignalKind::terminate())?;
+
+    tokio::spawn(async move {
+        loop {
+            #[cfg(unix)]
+            tokio::select! {
+                _ = signal::ctrl_c() => (),
+                _ = terminate.recv() => (),
+            }
+
+            #[cfg(not(unix))]
+            let _ = signal::ctrl_c().await;
+
+            if requested() {
+                eprintln!("\nForce quit, partial downloads may have to be restarted");
+                exit(130);
+            }
+
+            eprintln!("\nStopping after running requests, repeat to force quit");
+            request();
+        }
+    });
+
+    Ok(())
+}
+
+// also used to stop after fatal errors, like a full disk
+pub fn request() {
+    if !REQUESTED.swap(true, Relaxed) {
+        NOTIFY.notify_waiters();
+    }
+}
+
+pub fn requested() -> bool {
+    REQUESTED.load(Relaxed)
+}
+
+pub async fn wait() {
+    let mut notified = pin!(NOTIFY.notified());
+    notified.as_mut().enable();
+
+    if requested() {
+        return;
+    }
+
+    notified.await;
+}
+
+// sleeps until the duration has passed or shutdown was requested
+pub async fn sleep(duration: Duration) {
+    tokio::select! {
+        () = time::sleep(duration) => (),
+        () = wait() => (),
+    }
+}
@@ -1,14 +1,14 @@
-use crate::{ cli::ARGUMENTS, http::CLIENT };
-use anyhow::{ Context, Result, format_err, anyhow };
+use crate::{ cli::ARGUMENTS, post };
+use anyhow::{ Context, Result, format_err };
 use regex::{ Captures, Regex };
 use serde::Deserialize;
 use std::{
     collections::HashSet,
     fmt::{ self, Display, Formatter, Write },
-    fs::File,
-    io::{ BufRead, BufReader, Read },
+    fs::{ self, File },
+    io::{ BufRead, BufReader, ErrorKind, Read, Write as ioWrite },
     path::PathBuf,
-    sync::LazyLock,
+    sync::{ LazyLock, OnceLock },
 };
 use strum_macros::{ Display, EnumString };
 
@@ -38,69 +38,34 @@ impl FavoritePost {
             subtype: SubType::Post(self.id),
             path,
             archive_path,
+            creator_name: OnceLock::new(),
         }
     }
 }
 
 pub async fn try_fetch_favorites() -> Result<Vec<Target>> {
-    async fn try_fetch_artists(host: &str) -> Result<Vec<Info>> {
-        let mut tries = 0;
-
-        loop {
-            tries += 1;
-
-            let response = match
-                CLIENT.get(
-                    format!("https://{host}/api/v1/account/favorites?type=artist")
-                ).send().await
-            {
-                Ok(res) => res,
-                Err(err) if tries == ARGUMENTS.max_tries => {
-                    return Err(anyhow!(err));
-                }
-                Err(_) => {
-                    continue;
-                }
-            };
-
-            return Ok(response.json().await?);
-        }
+    async fn try_fetch_artists(base: &str) -> Result<Vec<Info>> {
+        let mut url = String::with_capacity(base.len() + 38);
+        let _ = write!(url, "{base}/api/v1/account/favorites?type=artist");
+        post::try_fetch_retrying(&url).await
     }
 
-    async fn try_fetch_posts(host: &str) -> Result<Vec<FavoritePost>> {
-        let mut tries = 0;
-
-        loop {
-            tries += 1;
-
-            let response = match
-                CLIENT.get(
-                    format!("https://{host}/api/v1/account/favorites?type=post")
-                ).send().await
-            {
-                Ok(res) => res,
-                Err(err) if tries == ARGUMENTS.max_tries => {
-                    return Err(anyhow!(err));
-                }
-                Err(_) => {
-                    continue;
-                }
-            };
-
-            return Ok(response.json().await?);
-        }
+    async fn try_fetch_posts(base: &str) -> Result<Vec<FavoritePost>> {
+        let mut url = String::with_capacity(base.len() + 36);
+        let _ = write!(url, "{base}/api/v1/account/favorites?type=post");
+        post::try_fetch_retrying(&url).await
     }
 
     let (mut artists, mut posts) = (Vec::new(), Vec::new());
 
     if ARGUMENTS.coomer_user.is_some() {
-        artists.append(&mut try_fetch_artists("coomer.st").await?);
-        posts.append(&mut try_fetch_posts("coomer.st").await?);
+        artists.append(&mut try_fetch_artists(Site::Coomer.base_url()).await?);
+        posts.append(&mut try_fetch_posts(Site::Coomer.base_url()).await?);
     }
 
     if ARGUMENTS.kemono_user.is_some() {
-        artists.append(&mut try_fetch_artists("kemono.cr").await?);
-        posts.append(&mut try_fetch_posts("kemono.cr").await?);
+        artists.append(&mut try_fetch_artists(Site::Kemono.base_url()).await?);
+        posts.append(&mut try_fetch_posts(Site::Kemono.base_url()).await?);
     }
 
     let mut targets = Vec::with_capacity(artists.len() + posts.len());
@@ -118,6 +83,7 @@ pub async fn try_fetch_favorites() -> Result<Vec<Target>> {
             subtype: SubType::None,
             path,
             archive_path,
+            creator_name: OnceLock::new(),
         });
     }
 
@@ -135,6 +101,8 @@ pub enum Target {
         subtype: SubType,
         path: PathBuf,
         archive_path: PathBuf,
+        // set once the creator profile has been fetched
+        creator_name: OnceLock<String>,
     },
     Discord {
         server: String,
@@ -153,6 +121,8 @@ impl Display for Target {
 
                 if let SubType::Post(p) = subtype {
                     let _ = write!(f, "/{p}");
+                } else if let Some(dir) = subtype.as_dir() {
+                    let _ = write!(f, "/{dir}");
                 }
             }
             Target::Discord { server, channel, .. } => {
@@ -172,9 +142,26 @@ impl Display for Target {
 pub enum SubType {
     PageOffset(usize),
     Post(String),
+    Dms,
+    Fancards,
+    Announcements,
+    Community,
     None,
 }
 
+impl SubType {
+    // creator content outside of regular posts is stored in a subdirectory
+    pub fn as_dir(&self) -> Option<&'static str> {
+        match self {
+            SubType::Dms => Some("dms"),
+            SubType::Fancards => Some("fancards"),
+            SubType::Announcements => Some("announcements"),
+            SubType::Community => Some("community"),
+            SubType::PageOffset(_) | SubType::Post(_) | SubType::None => None,
+        }
+    }
+}
+
 #[derive(Deserialize, Debug)]
 struct Info {
     id: String, // "5564244",
@@ -191,95 +178,124 @@ type LazyRegex = LazyLock<Regex>;
 
 static RE_LINKED: LazyRegex = LazyLock::new(|| {
     Regex::new(
-        r"^(?:https://)?(?:coomer\.(?:su|st|party)|kemono\.(?:su|cr|party))/(?<service>[a-z]+)/user/(?<user>[a-z|A-Z|0-9|\-|_|\.]+)/links$"
+        &format!(
+            r"^(?:https?://)?(?:{coomer}|{kemono})/(?<service>[a-z]+)/user/(?<user>[a-z|A-Z|0-9|\-|_|\.]+)/links$",
+            coomer = Site::Coomer.host_pattern(),
+            kemono = Site::Kemono.host_pattern()
+        )
     ).unwrap()
 });
 
 static RE_CREATOR: LazyRegex = LazyLock::new(|| {
     Regex::new(
-        r"^(?:https://)?(?:coomer\.(?:su|st|party)|kemono\.(?:su|cr|party))/(?<service>[a-z]+)/user/(?<user>[a-z|A-Z|0-9|\-|_|\.]+)$"
+        &format!(
+            r"^(?:https?://)?(?:{coomer}|{kemono})/(?<service>[a-z]+)/user/(?<user>[a-z|A-Z|0-9|\-|_|\.]+)$",
+            coomer = Site::Coomer.host_pattern(),
+            kemono = Site::Kemono.host_pattern()
+        )
     ).unwrap()
 });
 
 static RE_PAGE: LazyRegex = LazyLock::new(|| {
     Regex::new(
-        r"^(?:https://)?(?:coomer\.(?:su|st|party)|kemono\.(?:su|cr|party))/(?<service>[a-z]+)/user/(?<user>[a-z|A-Z|0-9|\-|_|\.]+)\?o=(?<offset>(0|50|[1-9]+(0|5)0))$"
+        &format!(
+            r"^(?:https?://)?(?:{coomer}|{kemono})/(?<service>[a-z]+)/user/(?<user>[a-z|A-Z|0-9|\-|_|\.]+)\?o=(?<offset>(0|50|[1-9]+(0|5)0))$",
+            coomer = Site::Coomer.host_pattern(),
+            kemono = Site::Kemono.host_pattern()
+        )
     ).unwrap()
 });
 
 static RE_POST: LazyRegex = LazyLock::new(|| {
     Regex::new(
-        r"^(?:https://)?(?:coomer\.(?:su|st|party)|kemono\.(?:su|cr|party))/(?<service>[a-z]+)/user/(?<user>[a-z|A-Z|0-9|\-|_|\.]+)/post/(?<post>[a-z|A-Z|0-9|\-|_|\.]+)$"
+        &format!(
+            r"^(?:https?://)?(?:{coomer}|{kemono})/(?<service>[a-z]+)/user/(?<user>[a-z|A-Z|0-9|\-|_|\.]+)/post/(?<post>[a-z|A-Z|0-9|\-|_|\.]+)$",
+            coomer = Site::Coomer.host_pattern(),
+            kemono = Site::Kemono.host_pattern()
+        )
+    ).unwrap()
+});
+
+static RE_DMS: LazyRegex = LazyLock::new(|| {
+    Regex::new(
+        &format!(
+            r"^(?:https?://)?(?:{coomer}|{kemono})/(?<service>[a-z]+)/user/(?<user>[a-z|A-Z|0-9|\-|_|\.]+)/dms$",
+            coomer = Site::Coomer.host_pattern(),
+            kemono = Site::Kemono.host_pattern()
+        )
+    ).unwrap()
+});
+
+static RE_FANCARDS: LazyRegex = LazyLock::new(|| {
+    Regex::new(
+        &format!(
+            r"^(?:https?://)?(?:{coomer}|{kemono})/(?<service>[a-z]+)/user/(?<user>[a-z|A-Z|0-9|\-|_|\.]+)/fancards$",
+            coomer = Site::Coomer.host_pattern(),
+            kemono = Site::Kemono.host_pattern()
+        )
+    ).unwrap()
+});
+
+static RE_ANNOUNCEMENTS: LazyRegex = LazyLock::new(|| {
+    Regex::new(
+        &format!(
+            r"^(?:https?://)?(?:{coomer}|{kemono})/(?<service>[a-z]+)/user/(?<user>[a-z|A-Z|0-9|\-|_|\.]+)/announcements$",
+            coomer = Site::Coomer.host_pattern(),
+            kemono = Site::Kemono.host_pattern()
+        )
+    ).unwrap()
+});
+
+static RE_COMMUNITY: LazyRegex = LazyLock::new(|| {
+    Regex::new(
+        &format!(
+            r"^(?:https?://)?(?:{coomer}|{kemono})/(?<service>[a-z]+)/user/(?<user>[a-z|A-Z|0-9|\-|_|\.]+)/community$",
+            coomer = Site::Coomer.host_pattern(),
+            kemono = Site::Kemono.host_pattern()
+        )
     ).unwrap()
 });
 
 static RE_DISCORD: LazyRegex = LazyLock::new(|| {
     Regex::new(
-        r"^(?:https://)?kemono\.(?:su|cr|party)/discord/server/(?<server>[0-9]{17,19})(/(?<channel>[0-9]{17,19}))?$"
+        &format!(
+            r"^(?:https?://)?(?:{kemono})/discord/server/(?<server>[0-9]{{17,19}})(/(?<channel>[0-9]{{17,19}}))?$",
+            kemono = Site::Kemono.host_pattern()
+        )
     ).unwrap()
 });
 
 static RE_DISCORD_PAGE: LazyRegex = LazyLock::new(|| {
     Regex::new(
-        r"^(?:https://)?kemono\.(?:su|cr|party)/discord/server/(?<server>[0-9]{17,19})(/(?<channel>[0-9]{17,19}))?(\?o=(?<offset>\d+))?$"
+        &format!(
+            r"^(?:https?://)?(?:{kemono})/discord/server/(?<server>[0-9]{{17,19}})(/(?<channel>[0-9]{{17,19}}))?(\?o=(?<offset>\d+))?$",
+            kemono = Site::Kemono.host_pattern()
+        )
     ).unwrap()
 });
 
 async fn try_fetch_linked_accounts(service: Service, user: &str) -> Result<Vec<Info>> {
-    let host = service.host();
+    let base = service.base_url();
     let service = service.as_static_str();
 
     let mut accounts = Vec::with_capacity(4);
 
     {
         let mut url = String::with_capacity(
-            8 + host.len() + 8 + service.len() + 6 + user.len() + 8
+            base.len() + 8 + service.len() + 6 + user.len() + 8
         );
-        let _ = write!(url, "https://{host}/api/v1/{service}/user/{user}/profile");
-
-        let mut tries = 0;
+        let _ = write!(url, "{base}/api/v1/{service}/user/{user}/profile");
 
-        loop {
-            tries += 1;
-
-            match CLIENT.get(&url).send().await {
-                Ok(res) => {
-                    accounts.push(res.json().await?);
-                    break;
-                }
-                Err(err) => {
-                    if tries == ARGUMENTS.max_tries {
-                        return Err(anyhow!(err));
-                    }
-                }
-            }
-        }
+        accounts.push(post::try_fetch_retrying(&url).await?);
     }
 
     {
         let mut linked_accounts_url = String::with_capacity(
-            8 + host.len() + 8 + service.len() + 6 + user.len() + 6
+            base.len() + 8 + service.len() + 6 + user.len() + 6
         );
-        let _ = write!(linked_accounts_url, "https://{host}/api/v1/{service}/user/{user}/links");
-
-        let mut tries = 0;
+        let _ = write!(linked_accounts_url, "{base}/api/v1/{service}/user/{user}/links");
 
-        loop {
-            tries += 1;
-
-            match CLIENT.get(&linked_accounts_url).send().await {
-                Ok(res) => {
-                    let mut linked_accounts = res.json().await?;
-                    accounts.append(&mut linked_accounts);
-                    break;
-                }
-                Err(err) => {
-                    if tries == ARGUMENTS.max_tries {
-                        return Err(anyhow!(err));
-                    }
-                }
-            }
-        }
+        accounts.append(&mut post::try_fetch_retrying(&linked_accounts_url).await?);
     }
 
     Ok(accounts)
@@ -380,6 +396,7 @@ impl Target {
                         subtype: SubType::None,
                         path,
                         archive_path,
+                        creator_name: OnceLock::new(),
                     }
                 };
 
@@ -401,6 +418,7 @@ impl Target {
                 subtype: SubType::None,
                 path,
                 archive_path,
+                creator_name: OnceLock::new(),
             }
         } else if RE_PAGE.is_match(url) {
             let caps = capture(&RE_PAGE);
@@ -414,6 +432,7 @@ impl Target {
                 subtype: SubType::PageOffset(extract_unwrap(&caps, "offset").parse()?),
                 path,
                 archive_path,
+                creator_name: OnceLock::new(),
             }
         } else if RE_POST.is_match(url) {
             let caps = capture(&RE_POST);
@@ -427,6 +446,34 @@ impl Target {
                 subtype: SubType::Post(extract_unwrap(&caps, "post")),
                 path,
                 archive_path,
+                creator_name: OnceLock::new(),
+            }
+        } else if
+            let Some((re, subtype)) = [
+                (&RE_DMS, SubType::Dms),
+                (&RE_FANCARDS, SubType::Fancards),
+                (&RE_ANNOUNCEMENTS, SubType::Announcements),
+                (&RE_COMMUNITY, SubType::Community),
+            ]
+                .into_iter()
+                .find(|(re, _)| re.is_match(url))
+        {
+            let caps = capture(re);
+
+            let (service, user) = service_user(&caps)?;
+            let (mut path, archive_path) = make_paths(service, &user);
+
+            if let Some(dir) = subtype.as_dir() {
+                path.push(dir);
+            }
+
+            Target::Creator {
+                service,
+                user,
+                subtype,
+                path,
+                archive_path,
+                creator_name: OnceLock::new(),
             }
         } else if RE_DISCORD.is_match(url) {
             let caps = capture(&RE_DISCORD);
@@ -469,24 +516,159 @@ impl Target {
     }
 
     pub fn try_read_archive(&self) -> Result<HashSet<String>> {
-        let mut archive = File::options()
+        let dry_run = ARGUMENTS.is_dry_run();
+
+        let archive = File::options()
+            .read(true)
+            .append(!dry_run)
+            .create(!dry_run)
+            .truncate(false)
+            .open(self.as_archive_pathbuf());
+
+        if dry_run && archive.as_ref().is_err_and(|err| err.kind() == ErrorKind::NotFound) {
+            return Ok(HashSet::new());
+        }
+
+        let mut archive = archive.with_context(|| {
+            let file = self.to_string();
+            let mut buf = String::with_capacity(32 + file.len());
+            let _ = write!(buf, "Failed to open archive file for {file}");
+            buf
+        })?;
+
+        let mut arc_buf = String::new();
+
+        archive.read_to_string(&mut arc_buf)?;
+
+        Ok(arc_buf.lines().map(ToString::to_string).collect())
+    }
+
+    // forgets hashes of deleted files, so they are downloaded again with --download-archive
+    pub fn try_remove_from_archive(&self, hashes: &HashSet<String>) -> Result<()> {
+        let path = self.as_archive_pathbuf();
+
+        let archive = match fs::read_to_string(path) {
+            Ok(archive) => archive,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                return Ok(());
+            }
+            Err(err) => {
+                return Err(err.into());
+            }
+        };
+
+        let mut buf = String::with_capacity(archive.len());
+
+        for line in archive.lines().filter(|line| !hashes.contains(*line)) {
+            buf.push_str(line);
+            buf.push('\n');
+        }
+
+        fs::write(path, buf).with_context(|| {
+            let file = self.to_string();
+            let mut buf = String::with_capacity(33 + file.len());
+            let _ = write!(buf, "Failed to write archive file for {file}");
+            buf
+        })
+    }
+
+    // the target resolves all files stored in its directory (see --prune)
+    pub fn is_whole_dir(&self) -> bool {
+        match self {
+            Target::Creator { subtype, .. } => {
+                !matches!(subtype, SubType::PageOffset(_) | SubType::Post(_))
+            }
+            Target::Discord { channel, .. } => channel.is_none(),
+        }
+    }
+
+    pub fn as_user(&self) -> &str {
+        match self {
+            Target::Creator { user, .. } => user,
+            Target::Discord { server, .. } => server,
+        }
+    }
+
+    pub fn as_creator_name(&self) -> &str {
+        match self {
+            Target::Creator { user, creator_name, .. } => {
+                creator_name.get().map_or(user, String::as_str)
+            }
+            Target::Discord { server, .. } => server,
+        }
+    }
+
+    pub fn set_creator_name(&self, name: String) {
+        if let Target::Creator { creator_name, .. } = self {
+            let _ = creator_name.set(name);
+        }
+    }
+
+    pub fn as_subtype_dir(&self) -> Option<&'static str> {
+        match self {
+            Target::Creator { subtype, .. } => subtype.as_dir(),
+            Target::Discord { .. } => None,
+        }
+    }
+
+    pub fn to_seen_pathbuf(&self) -> PathBuf {
+        self.as_archive_pathbuf().with_extension("posts.txt")
+    }
+
+    pub fn try_read_seen(&self) -> Result<HashSet<String>> {
+        let dry_run = ARGUMENTS.is_dry_run();
+
+        let seen = File::options()
             .read(true)
+            .append(!dry_run)
+            .create(!dry_run)
+            .truncate(false)
+            .open(self.to_seen_pathbuf());
+
+        if dry_run && seen.as_ref().is_err_and(|err| err.kind() == ErrorKind::NotFound) {
+            return Ok(HashSet::new());
+        }
+
+        let mut seen = seen.with_context(|| {
+            let file = self.to_string();
+            let mut buf = String::with_capacity(34 + file.len());
+            let _ = write!(buf, "Failed to open seen posts file for {file}");
+            buf
+        })?;
+
+        let mut seen_buf = String::new();
+
+        seen.read_to_string(&mut seen_buf)?;
+
+        Ok(seen_buf.lines().map(ToString::to_string).collect())
+    }
+
+    pub fn try_write_seen(&self, post_ids: &[String]) -> Result<()> {
+        if post_ids.is_empty() || ARGUMENTS.is_dry_run() {
+            return Ok(());
+        }
+
+        let mut seen = File::options()
             .append(true)
             .create(true)
-            .truncate(false)
-            .open(self.as_archive_pathbuf())
+            .open(self.to_seen_pathbuf())
             .with_context(|| {
                 let file = self.to_string();
-                let mut buf = String::with_capacity(32 + file.len());
-                let _ = write!(buf, "Failed to open archive file for {file}");
+                let mut buf = String::with_capacity(34 + file.len());
+                let _ = write!(buf, "Failed to open seen posts file for {file}");
                 buf
             })?;
 
-        let mut arc_buf = String::new();
+        let mut buf = String::with_capacity(post_ids.iter().map(|id| id.len() + 1).sum());
 
-        archive.read_to_string(&mut arc_buf)?;
+        for id in post_ids {
+            buf.push_str(id);
+            buf.push('\n');
+        }
 
-        Ok(arc_buf.lines().map(ToString::to_string).collect())
+        seen.write_all(buf.as_bytes())?;
+
+        Ok(())
     }
 
     pub fn as_pathbuf(&self) -> &PathBuf {
@@ -542,14 +724,47 @@ impl Service {
         }
     }
 
-    pub fn host(self) -> &'static str {
+    pub fn site(self) -> Site {
         #[allow(clippy::enum_glob_use)]
         use Service::*;
         match self {
-            CandFans | Fansly | OnlyFans => "coomer.st",
-            _ => "kemono.cr",
+            CandFans | Fansly | OnlyFans => Site::Coomer,
+            _ => Site::Kemono,
         }
     }
+
+    pub fn base_url(self) -> &'static str {
+        self.site().base_url()
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Site {
+    Coomer,
+    Kemono,
+}
+
+impl Site {
+    // scheme://host[:port][/path] without a trailing slash, e.g. "https://kemono.cr"
+    pub fn base_url(self) -> &'static str {
+        match self {
+            Site::Coomer => &ARGUMENTS.coomer_url,
+            Site::Kemono => &ARGUMENTS.kemono_url,
+        }
+    }
+
+    fn host_pattern(self) -> String {
+        let (known, base) = match self {
+            Site::Coomer => (r"coomer\.(?:su|st|party)", self.base_url()),
+            Site::Kemono => (r"kemono\.(?:su|cr|party)", self.base_url()),
+        };
+
+        let host = base.split_once("://").map_or(base, |(_, host)| host);
+
+        let mut pattern = String::with_capacity(known.len() + 1 + host.len() * 2);
+        let _ = write!(pattern, "{known}|{}", regex::escape(host));
+        pattern
+    }
 }
 
 fn make_pathbuf(service: Service, user: &str) -> PathBuf {
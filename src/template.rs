@@ -0,0 +1,197 @@
+use crate::{ file::{ PostFile, sanitize, truncate }, target::Target };
+use chrono::format::{ Item, StrftimeItems };
+use serde::Deserialize;
+use std::fmt::Write;
+
+const DEFAULT_DATE_FORMAT: &str = "%Y-%m-%d";
+
+// Path/file name template, e.g. "{service}/{creator_name}/{published:%Y-%m}".
+// Each '/' separated component is rendered and sanitized on its own,
+// so placeholder values can never introduce additional directories.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(try_from = "String")]
+pub struct Template {
+    components: Vec<Vec<Segment>>,
+}
+
+#[derive(Clone, Debug)]
+enum Segment {
+    Literal(String),
+    Field(Field, Option<String>),
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Field {
+    Service,
+    User,
+    CreatorName,
+    PostId,
+    PostTitle,
+    Published,
+    Index,
+    Name,
+    Hash,
+    Ext,
+}
+
+impl TryFrom<String> for Template {
+    type Error = String;
+
+    fn try_from(template: String) -> Result<Self, Self::Error> {
+        Self::parse(&template)
+    }
+}
+
+impl Template {
+    pub fn parse(template: &str) -> Result<Self, String> {
+        let mut components = Vec::new();
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+
+        let mut chars = template.chars();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' => {
+                    let mut placeholder = String::new();
+
+                    loop {
+                        match chars.next() {
+                            Some('}') => {
+                                break;
+                            }
+                            Some(c) => placeholder.push(c),
+                            None => {
+                                return Err(format!("unclosed placeholder in template: {template}"));
+                            }
+                        }
+                    }
+
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+
+                    segments.push(Self::parse_placeholder(&placeholder)?);
+                }
+                '}' => {
+                    return Err(format!("unopened placeholder in template: {template}"));
+                }
+                '/' | '\\' => {
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+
+                    if !segments.is_empty() {
+                        components.push(std::mem::take(&mut segments));
+                    }
+                }
+                c => literal.push(c),
+            }
+        }
+
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+
+        if !segments.is_empty() {
+            components.push(segments);
+        }
+
+        if components.is_empty() {
+            return Err(String::from("template must not be empty"));
+        }
+
+        Ok(Self { components })
+    }
+
+    fn parse_placeholder(placeholder: &str) -> Result<Segment, String> {
+        let (name, format) = match placeholder.split_once(':') {
+            Some((name, format)) => (name, Some(format.to_string())),
+            None => (placeholder, None),
+        };
+
+        let field = match name {
+            "service" => Field::Service,
+            "user" => Field::User,
+            "creator_name" => Field::CreatorName,
+            "post_id" => Field::PostId,
+            "post_title" => Field::PostTitle,
+            "published" => Field::Published,
+            "index" => Field::Index,
+            "name" => Field::Name,
+            "hash" => Field::Hash,
+            "ext" => Field::Ext,
+            _ => {
+                return Err(format!("unknown placeholder: {{{placeholder}}}"));
+            }
+        };
+
+        if let Some(format) = &format {
+            if !matches!(field, Field::Published) {
+                return Err(format!("placeholder does not take a format: {{{placeholder}}}"));
+            }
+
+            if StrftimeItems::new(format).any(|item| matches!(item, Item::Error)) {
+                return Err(format!("invalid date format: {{{placeholder}}}"));
+            }
+        }
+
+        Ok(Segment::Field(field, format))
+    }
+
+    // sanitized path components, empty components are skipped
+    pub fn render(&self, target: &Target, file: &PostFile) -> Vec<String> {
+        let mut components = Vec::with_capacity(self.components.len());
+
+        for segments in &self.components {
+            let mut component = String::new();
+
+            for segment in segments {
+                match segment {
+                    Segment::Literal(literal) => component.push_str(literal),
+                    Segment::Field(field, format) => {
+                        Self::render_field(&mut component, *field, format.as_deref(), target, file);
+                    }
+                }
+            }
+
+            if !component.trim().is_empty() {
+                components.push(sanitize(&component));
+            }
+        }
+
+        components
+    }
+
+    fn render_field(
+        buf: &mut String,
+        field: Field,
+        format: Option<&str>,
+        target: &Target,
+        file: &PostFile
+    ) {
+        let post = file.get_post();
+
+        match field {
+            Field::Service => buf.push_str(target.as_service().as_static_str()),
+            Field::User => buf.push_str(target.as_user()),
+            Field::CreatorName => buf.push_str(target.as_creator_name()),
+            Field::PostId => buf.push_str(post.id.as_deref().unwrap_or_default()),
+            // capped like post directories and original names, components are limited to 255 bytes
+            Field::PostTitle => {
+                buf.push_str(truncate(post.title.as_deref().unwrap_or_default().trim(), 128));
+            }
+            Field::Published => {
+                if let Some(published) = post.published {
+                    let _ = write!(buf, "{}", published.format(format.unwrap_or(DEFAULT_DATE_FORMAT)));
+                }
+            }
+            Field::Index => {
+                let _ = write!(buf, "{}", file.get_index());
+            }
+            Field::Name => buf.push_str(truncate(file.get_original_stem(), 128)),
+            Field::Hash => buf.push_str(file.get_hash().unwrap_or_default()),
+            Field::Ext => buf.push_str(file.get_ext().unwrap_or_default()),
+        }
+    }
+}
@@ -0,0 +1,298 @@
+use crate::{
+    cli::{ ARGUMENTS, VerifyAction },
+    file::HASH_RE,
+    pretty::with_word,
+    shutdown,
+    target::Target,
+};
+use anyhow::{ Context, Result };
+use futures::stream;
+use futures_util::StreamExt;
+use indicatif::{ ProgressBar, ProgressStyle };
+use std::{
+    collections::HashSet,
+    fmt::Write,
+    fs,
+    path::{ Path, PathBuf },
+    sync::atomic::{ AtomicBool, Ordering::Relaxed },
+    thread,
+    time::Duration,
+};
+use tokio::task;
+
+// files written next to downloads by --write-json, --write-content and --extract-links
+const KNOWN_NAMES: [&str; 2] = ["links.txt", "links.jsonl"];
+const KNOWN_SUFFIXES: [&str; 3] = [".post.json", ".post.md", ".post.html"];
+// metadata and content files of earlier versions, named "{post_id}.{ext}"
+const KNOWN_EXTS: [&str; 3] = ["json", "md", "html"];
+
+pub static VERIFY_FAILED: AtomicBool = AtomicBool::new(false);
+
+pub enum Kind {
+    Hashed(String),
+    Temp,
+    Known,
+    Unexpected,
+}
+
+// Re-hashes all files of a target whose name contains a hash (--verify).
+// Mismatched files are deleted or quarantined depending on the action, and
+// removed from the download archive, so the next run downloads them again.
+pub async fn try_verify(target: &Target, action: VerifyAction) -> Result<()> {
+    let root = target.as_pathbuf();
+
+    if !root.is_dir() {
+        eprintln!("{target}: nothing to verify, {} does not exist", root.display());
+        return Ok(());
+    }
+
+    let mut hashed = Vec::new();
+    let mut temps = Vec::new();
+    let mut unexpected = Vec::new();
+
+    for path in try_walk(root, &[])? {
+        match classify(&path) {
+            Kind::Hashed(hash) => hashed.push((path, hash)),
+            Kind::Temp => temps.push(path),
+            Kind::Known => (),
+            Kind::Unexpected => unexpected.push(path),
+        }
+    }
+
+    let bar = ProgressBar::new(hashed.len() as u64);
+
+    bar.set_style(
+        ProgressStyle::with_template(
+            "[{elapsed_precise}] {bar:40.cyan/blue} {human_pos:>8}/{human_len:8} ({percent}%) {msg}"
+        )
+            .unwrap()
+            .progress_chars("##-")
+    );
+
+    bar.enable_steady_tick(Duration::from_millis(200));
+
+    // hashing is bound by disk and CPU, more threads than cores don't help
+    let threads = thread::available_parallelism().map_or(1, usize::from).min(ARGUMENTS.threads());
+
+    let mut results = stream
+        ::iter(hashed)
+        .map(|(path, hash)| {
+            task::spawn_blocking(move || {
+                let result = sha256::try_digest(path.as_path());
+                (path, hash, result)
+            })
+        })
+        .buffer_unordered(threads);
+
+    let mut mismatched = Vec::new();
+    let mut checked = 0;
+    let mut unreadable = 0;
+
+    // files that are being hashed are finished first
+    while !shutdown::requested() && let Some(result) = results.next().await {
+        let (path, hash, result) = result?;
+
+        match result {
+            Ok(actual) if actual == hash => (),
+            Ok(_) => mismatched.push((path, hash)),
+            Err(err) => {
+                bar.println(format!("unreadable: {}: {err}", path.display()));
+                unreadable += 1;
+            }
+        }
+
+        checked += 1;
+        bar.inc(1);
+    }
+
+    bar.finish_and_clear();
+
+    mismatched.sort();
+    temps.sort();
+    unexpected.sort();
+
+    // Views (--objects) are symlinks, the object they point to holds the mismatched
+    // contents. It is removed along with the view, otherwise the next run would link
+    // it again. Views of other targets are relinked once the object was downloaded again.
+    // Other symlinks (--dedup) point to files of other targets, only the link is removed.
+    let objects_dir = Path::new(&ARGUMENTS.output_path).join("objects");
+    let mut objects = Vec::new();
+
+    for (path, _) in &mismatched {
+        if path.is_symlink() {
+            let linked = try_resolve_link(path)?;
+
+            if action == VerifyAction::Report {
+                eprintln!("mismatch: {} -> {}", path.display(), linked.display());
+            } else {
+                try_delete(path)?;
+                eprintln!("mismatch (link deleted): {}", path.display());
+            }
+
+            if linked.starts_with(&objects_dir) && !objects.contains(&linked) {
+                objects.push(linked);
+            }
+
+            continue;
+        }
+
+        match action {
+            VerifyAction::Report => eprintln!("mismatch: {}", path.display()),
+            VerifyAction::Delete => {
+                try_delete(path)?;
+                eprintln!("mismatch (deleted): {}", path.display());
+            }
+            VerifyAction::Quarantine => {
+                let dest = try_move_aside(path, "quarantine")?;
+                eprintln!("mismatch (quarantined): {} -> {}", path.display(), dest.display());
+            }
+        }
+    }
+
+    for object in &objects {
+        match action {
+            VerifyAction::Report => (),
+            VerifyAction::Delete => {
+                try_delete(object)?;
+                eprintln!("mismatch (deleted): {}", object.display());
+            }
+            VerifyAction::Quarantine => {
+                let dest = try_move_aside(object, "quarantine")?;
+                eprintln!("mismatch (quarantined): {} -> {}", object.display(), dest.display());
+            }
+        }
+    }
+
+    if action != VerifyAction::Report && !mismatched.is_empty() {
+        target.try_remove_from_archive(
+            &mismatched
+                .iter()
+                .map(|(_, hash)| hash.clone())
+                .collect::<HashSet<String>>()
+        )?;
+    }
+
+    for path in &temps {
+        eprintln!("temporary: {}", path.display());
+    }
+
+    for path in &unexpected {
+        eprintln!("unexpected: {}", path.display());
+    }
+
+    let mut summary = String::with_capacity(128);
+
+    let _ = write!(
+        summary,
+        "{target}: verified {}, {} mismatched",
+        with_word(checked - unreadable, "file"),
+        mismatched.len()
+    );
+
+    if unreadable != 0 {
+        let _ = write!(summary, ", {unreadable} unreadable");
+    }
+
+    let _ = write!(summary, ", {} temporary, {} unexpected", temps.len(), unexpected.len());
+
+    eprintln!("{summary}");
+
+    if !mismatched.is_empty() || unreadable != 0 {
+        VERIFY_FAILED.store(true, Relaxed);
+    }
+
+    Ok(())
+}
+
+// regular files and symlinks below a target directory, except for the skipped
+// directories. Symlinks are not followed.
+pub fn try_walk(root: &Path, skip: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![root.to_path_buf()];
+
+    while let Some(dir) = dirs.pop() {
+        let entries = fs::read_dir(&dir).with_context(|| {
+            let mut buf = String::with_capacity(16 + dir.as_os_str().len());
+            let _ = write!(buf, "read directory: {}", dir.display());
+            buf
+        })?;
+
+        for entry in entries {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+
+            if file_type.is_dir() {
+                let path = entry.path();
+
+                if !skip.contains(&path) {
+                    dirs.push(path);
+                }
+            } else if file_type.is_file() || file_type.is_symlink() {
+                files.push(entry.path());
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+// the file a symlink points to, below the output path like all other paths
+fn try_resolve_link(path: &Path) -> Result<PathBuf> {
+    let linked = fs::canonicalize(path)?;
+    let output = fs::canonicalize(&ARGUMENTS.output_path)?;
+
+    Ok(match linked.strip_prefix(&output) {
+        Ok(relative) => Path::new(&ARGUMENTS.output_path).join(relative),
+        Err(_) => linked,
+    })
+}
+
+pub fn classify(path: &Path) -> Kind {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+
+    if name.ends_with(".temp") || name.ends_with(".temp.parts") || name.ends_with(".partial") {
+        Kind::Temp
+    } else if KNOWN_SUFFIXES.iter().any(|suffix| name.ends_with(suffix)) {
+        Kind::Known
+    } else if let Some(caps) = HASH_RE.captures(&name) {
+        Kind::Hashed(caps["hash"].to_string())
+    } else if
+        KNOWN_NAMES.contains(&name.as_ref()) ||
+        path
+            .extension()
+            .is_some_and(|ext| KNOWN_EXTS.iter().any(|known| ext == *known))
+    {
+        Kind::Known
+    } else {
+        Kind::Unexpected
+    }
+}
+
+pub fn try_delete(path: &Path) -> Result<()> {
+    fs::remove_file(path).with_context(|| {
+        let mut buf = String::with_capacity(13 + path.as_os_str().len());
+        let _ = write!(buf, "delete file: {}", path.display());
+        buf
+    })
+}
+
+// "{output}/{dir}/{service}/{user}/...", keeping the path relative to the output directory
+pub fn try_move_aside(path: &Path, dir: &str) -> Result<PathBuf> {
+    let relative = path.strip_prefix(&ARGUMENTS.output_path).unwrap_or(path);
+
+    let dest = PathBuf::from_iter([&ARGUMENTS.output_path, dir]).join(relative);
+
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    fs::rename(path, &dest).with_context(|| {
+        let mut buf = String::with_capacity(
+            16 + dir.len() + path.as_os_str().len() + dest.as_os_str().len()
+        );
+        let _ = write!(buf, "move file to {dir}: {} -> {}", path.display(), dest.display());
+        buf
+    })?;
+
+    Ok(dest)
+}